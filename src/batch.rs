@@ -0,0 +1,390 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use colored::Colorize;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    cache::RenderCache,
+    cli::ConvertOpt,
+    converter::{ConvertResult, Converter},
+    error::{Result, Txt2EpubError},
+    lint::Severity,
+    txt2epub,
+    validate::validate_path,
+};
+
+pub enum FileStatus {
+    Ok(ConvertResult),
+    Failed(Txt2EpubError),
+    /// not converted because an earlier file failed with `--fail-fast`.
+    Skipped,
+}
+
+pub struct FileReport {
+    pub path: PathBuf,
+    pub out_file: PathBuf,
+    pub elapsed: Duration,
+    pub status: FileStatus,
+}
+
+impl FileReport {
+    pub fn is_failed(&self) -> bool {
+        matches!(self.status, FileStatus::Failed(_))
+    }
+}
+
+/// Convert all files in parallel, in the order of `opts`.
+///
+/// With `fail_fast`, files that have not started yet are skipped once one fails.
+pub fn convert_all(opts: Vec<ConvertOpt>, fail_fast: bool) -> Vec<FileReport> {
+    let failed = AtomicBool::new(false);
+
+    opts.into_par_iter()
+        .map(|opt| {
+            let path = opt.path.clone();
+            let out_file = opt.out_file.clone();
+            let start = Instant::now();
+
+            let status = if fail_fast && failed.load(Ordering::Relaxed) {
+                FileStatus::Skipped
+            } else {
+                match txt2epub(opt) {
+                    Ok(result) => FileStatus::Ok(result),
+                    Err(err) => {
+                        failed.store(true, Ordering::Relaxed);
+                        FileStatus::Failed(err)
+                    }
+                }
+            };
+
+            FileReport {
+                path,
+                out_file,
+                elapsed: start.elapsed(),
+                status,
+            }
+        })
+        .collect()
+}
+
+/// Lint all files and print the issues, returns false if any file fails or has a denied issue.
+pub fn lint_all(opts: Vec<ConvertOpt>) -> bool {
+    let results = opts
+        .into_par_iter()
+        .map(|opt| {
+            let path = opt.path.clone();
+            let mut converter = Converter::from(opt);
+
+            let issues = converter
+                .parse_path(&path)
+                .map(|book| converter.lint(&book));
+
+            (path, issues)
+        })
+        .collect::<Vec<_>>();
+
+    let mut ok = true;
+    let (mut warnings, mut errors) = (0, 0);
+
+    for (path, issues) in results {
+        let issues = match issues {
+            Ok(issues) => issues,
+            Err(err) => {
+                ok = false;
+                println!("{}", err.to_string().red());
+                continue;
+            }
+        };
+
+        for issue in issues {
+            let line = format!("{}:{}", path.display(), issue);
+
+            if issue.severity == Severity::Deny {
+                ok = false;
+                errors += 1;
+                println!("{}", line.red());
+            } else {
+                warnings += 1;
+                println!("{}", line.yellow());
+            }
+        }
+    }
+
+    println!("\n{} errors, {} warnings.", errors, warnings);
+
+    ok
+}
+
+/// Validate all epub files and print the problems, returns false if any is invalid.
+pub fn validate_all(paths: Vec<PathBuf>) -> bool {
+    let results = paths
+        .into_par_iter()
+        .map(|path| {
+            let problems = validate_path(&path);
+            (path, problems)
+        })
+        .collect::<Vec<_>>();
+
+    let mut invalid = 0;
+
+    for (path, problems) in &results {
+        match problems {
+            Ok(problems) if problems.is_empty() => {
+                println!("{}: {}", path.display(), "ok".green());
+            }
+            Ok(problems) => {
+                invalid += 1;
+
+                for problem in problems {
+                    println!("{}", format!("{}: {}", path.display(), problem).red());
+                }
+            }
+            Err(err) => {
+                invalid += 1;
+                println!("{}", err.to_string().red());
+            }
+        }
+    }
+
+    println!("\n{} valid, {} invalid.", results.len() - invalid, invalid);
+
+    invalid == 0
+}
+
+/// Remove the cache entries older than `max_age`, or all of them.
+pub fn prune_cache(cache: &RenderCache, max_age: Option<Duration>) -> bool {
+    match cache.prune(max_age) {
+        Ok(stats) => {
+            println!(
+                "{}: removed {} chapters, {} freed.",
+                cache.dir().display(),
+                stats.removed,
+                format_size(stats.freed)
+            );
+            true
+        }
+        Err(e) => {
+            println!("{}", Txt2EpubError::io(cache.dir(), e).to_string().red());
+            false
+        }
+    }
+}
+
+pub fn print_summary(reports: &[FileReport]) {
+    println!(
+        "{:<8} {:>8} {:>10} {:>10}  file",
+        "status", "warnings", "time", "size"
+    );
+
+    for report in reports {
+        let (status, warnings, size) = match &report.status {
+            FileStatus::Ok(result) => (
+                "ok".green(),
+                result.warnings.len().to_string(),
+                format_size(result.stats.output_size),
+            ),
+            FileStatus::Failed(_) => ("failed".red(), "-".into(), "-".into()),
+            FileStatus::Skipped => ("skipped".yellow(), "-".into(), "-".into()),
+        };
+
+        println!(
+            "{:<8} {:>8} {:>9.3}s {:>10}  {}",
+            status,
+            warnings,
+            report.elapsed.as_secs_f32(),
+            size,
+            report.path.display()
+        );
+    }
+
+    let count = |f: fn(&FileStatus) -> bool| reports.iter().filter(|r| f(&r.status)).count();
+
+    println!(
+        "\n{} ok, {} failed, {} skipped, {} warnings.",
+        count(|s| matches!(s, FileStatus::Ok(_))),
+        count(|s| matches!(s, FileStatus::Failed(_))),
+        count(|s| matches!(s, FileStatus::Skipped)),
+        reports
+            .iter()
+            .map(|r| match &r.status {
+                FileStatus::Ok(result) => result.warnings.len(),
+                _ => 0,
+            })
+            .sum::<usize>()
+    );
+}
+
+#[derive(Serialize)]
+struct ReportEntry<'a> {
+    input: &'a Path,
+    status: &'static str,
+    error: Option<String>,
+    #[serde(flatten)]
+    result: Option<&'a ConvertResult>,
+}
+
+/// Write the reports as a json array, one entry per input.
+pub fn write_report(reports: &[FileReport], path: &Path) -> Result<()> {
+    let entries = reports
+        .iter()
+        .map(|report| {
+            let (status, error, result) = match &report.status {
+                FileStatus::Ok(result) => ("ok", None, Some(result)),
+                FileStatus::Failed(err) => ("failed", Some(err.to_string()), None),
+                FileStatus::Skipped => ("skipped", None, None),
+            };
+
+            ReportEntry {
+                input: &report.path,
+                status,
+                error,
+                result,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let file = File::create(path).map_err(|e| Txt2EpubError::io(path, e))?;
+    serde_json::to_writer_pretty(BufWriter::new(file), &entries)?;
+
+    Ok(())
+}
+
+fn format_size(size: u64) -> String {
+    match size {
+        s if s >= 1024 * 1024 => format!("{:.1}MB", s as f64 / 1024.0 / 1024.0),
+        s if s >= 1024 => format!("{:.1}KB", s as f64 / 1024.0),
+        s => format!("{}B", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use serde_json::Value;
+
+    use super::{convert_all, write_report, FileStatus};
+    use crate::cli::{ConvertOpt, OutputFormat};
+
+    fn options(dir: &std::path::Path, names: &[&str]) -> Vec<ConvertOpt> {
+        names
+            .iter()
+            .map(|name| ConvertOpt {
+                path: dir.join(name),
+                out_file: dir.join(name).with_extension("clean.txt"),
+                format: OutputFormat::Txt,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn statuses(opts: Vec<ConvertOpt>, fail_fast: bool) -> Vec<(PathBuf, &'static str)> {
+        // one thread takes the files in order, so which ones are left when a file fails is known.
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+
+        pool.install(|| convert_all(opts, fail_fast))
+            .into_iter()
+            .map(|report| {
+                let status = match report.status {
+                    FileStatus::Ok(_) => "ok",
+                    FileStatus::Failed(_) => "failed",
+                    FileStatus::Skipped => "skipped",
+                };
+
+                (report.path, status)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fail_fast() {
+        let dir = std::env::temp_dir().join(format!("txt2epub-batch-{}", std::process::id()));
+        let names = ["a.txt", "missing.txt", "b.txt", "c.txt"];
+
+        fs::create_dir_all(&dir).unwrap();
+
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(dir.join(name), "第一章 开始\n\n正文。\n").unwrap();
+        }
+
+        let paths = names.map(|name| dir.join(name));
+
+        assert_eq!(
+            statuses(options(&dir, &names), false),
+            paths
+                .clone()
+                .into_iter()
+                .zip(["ok", "failed", "ok", "ok"])
+                .collect::<Vec<_>>()
+        );
+
+        fs::remove_file(dir.join("b.clean.txt")).unwrap();
+
+        assert_eq!(
+            statuses(options(&dir, &names), true),
+            paths
+                .into_iter()
+                .zip(["ok", "failed", "skipped", "skipped"])
+                .collect::<Vec<_>>()
+        );
+        assert!(!dir.join("b.clean.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn report() {
+        let dir = std::env::temp_dir().join(format!("txt2epub-report-{}", std::process::id()));
+        let names = ["a.txt", "missing.txt", "b.txt"];
+        let report = dir.join("report.json");
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "第一章 开始\n\n正文。\n").unwrap();
+        fs::write(dir.join("b.txt"), "第一章 开始\n\n正文。\n").unwrap();
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let reports = pool.install(|| convert_all(options(&dir, &names), true));
+        write_report(&reports, &report).unwrap();
+
+        let entries: Value = serde_json::from_slice(&fs::read(&report).unwrap()).unwrap();
+        let entries = entries.as_array().unwrap();
+        assert_eq!(entries.len(), 3);
+
+        let ok = &entries[0];
+        assert_eq!(ok["input"], dir.join("a.txt").to_str().unwrap());
+        assert_eq!(ok["status"], "ok");
+        assert_eq!(ok["error"], Value::Null);
+        assert_eq!(ok["output"], dir.join("a.clean.txt").to_str().unwrap());
+        assert_eq!(ok["stats"]["chapters"], 1);
+        assert_eq!(ok["stats"]["characters"], 3);
+        assert!(ok["stats"]["output_size"].as_u64().unwrap() > 0);
+        assert_eq!(ok["checksum"].as_str().unwrap().len(), 64);
+        assert!(ok["warnings"].as_array().unwrap().is_empty());
+
+        let failed = &entries[1];
+        assert_eq!(failed["status"], "failed");
+        assert!(failed["error"].as_str().unwrap().contains("missing.txt"));
+        assert!(failed.get("stats").is_none());
+
+        let skipped = &entries[2];
+        assert_eq!(skipped["input"], dir.join("b.txt").to_str().unwrap());
+        assert_eq!(skipped["status"], "skipped");
+        assert_eq!(skipped["error"], Value::Null);
+        assert!(skipped.get("checksum").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,413 @@
+//! A cache of rendered chapters on disk, so a rebuild only renders the chapters that changed.
+//!
+//! An entry is keyed by the sha256 of the chapter content, the options the rendering depends on
+//! and [`struct@TEMPLATE_VERSION`]. Entries are never invalidated, they are left behind when a
+//! chapter changes and removed by [`RenderCache::prune`].
+
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use lazy_static::lazy_static;
+use log::debug;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cli::{ConvertOpt, HeadingStyle, OutputFormat},
+    heading::SectionKind,
+    novel_structure::chapter::{LineType, SerChapter},
+    NOVEL_CHAPTER_BODY_TEMPLATE, NOVEL_CHAPTER_TEMPLATE,
+};
+
+const EXTENSION: &str = "xhtml";
+
+/// Tells apart the temporary files of the threads writing the same entry.
+static TMP_ID: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// Changes with the chapter templates and the crate version. autocorrect, which rewrites the
+    /// cached text, is pinned to an exact version in `Cargo.toml` so that it only changes with the
+    /// crate version.
+    pub static ref TEMPLATE_VERSION: String = {
+        let mut hasher = Sha256::new();
+
+        for source in [
+            env!("CARGO_PKG_VERSION"),
+            NOVEL_CHAPTER_TEMPLATE,
+            NOVEL_CHAPTER_BODY_TEMPLATE,
+        ] {
+            hasher.update(source.len().to_le_bytes());
+            hasher.update(source);
+        }
+
+        format!("{:x}", hasher.finalize())
+    };
+}
+
+/// Everything the rendered chapter depends on, the line offsets are left out on purpose so
+/// editing a chapter does not invalidate the ones after it.
+#[derive(Serialize)]
+struct Key<'a> {
+    version: &'a str,
+    template: &'a str,
+    kepub: bool,
+    epub_version: u8,
+    replace_quote: bool,
+    label: &'a str,
+    /// the `<title>` of the page, set by the global title format.
+    global_title: &'a str,
+    heading_style: HeadingStyle,
+    no: usize,
+    part_no: usize,
+    title: &'a str,
+    raw_title: &'a str,
+    kind: SectionKind,
+    content: Vec<(&'a LineType, &'a str)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderCache {
+    dir: PathBuf,
+}
+
+#[derive(Debug, Default)]
+pub struct PruneStats {
+    pub removed: usize,
+    pub freed: u64,
+}
+
+impl RenderCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// `$XDG_CACHE_HOME/txt2epub`, or `~/.cache/txt2epub`.
+    pub fn default_dir() -> PathBuf {
+        std::env::var_os("XDG_CACHE_HOME")
+            .filter(|d| !d.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".cache")))
+            .unwrap_or_else(std::env::temp_dir)
+            .join("txt2epub")
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub(crate) fn key(&self, template: &str, chapter: &SerChapter, opt: &ConvertOpt) -> String {
+        let key = Key {
+            version: &TEMPLATE_VERSION,
+            template,
+            kepub: opt.format == OutputFormat::Kepub,
+            epub_version: opt.epub_version as u8,
+            replace_quote: opt.replace_quote,
+            label: &chapter.label,
+            global_title: &chapter.global_title,
+            heading_style: opt.heading_style,
+            no: chapter.no,
+            part_no: chapter.part_no,
+            title: &chapter.title,
+            raw_title: &chapter.raw_title,
+            kind: chapter.kind,
+            content: chapter
+                .content
+                .iter()
+                .map(|l| (&l.line_type, l.content.as_str()))
+                .collect(),
+        };
+
+        let mut hasher = Sha256::new();
+        // serializing borrowed strings can not fail.
+        serde_json::to_writer(&mut hasher, &key).unwrap();
+
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// The rendered chapter, a hit refreshes the entry so pruning by age keeps it.
+    pub(crate) fn get(&self, key: &str) -> Option<String> {
+        let path = self.path(key);
+        let html = fs::read_to_string(&path).ok()?;
+
+        if let Err(e) = File::options()
+            .append(true)
+            .open(&path)
+            .and_then(|f| f.set_modified(SystemTime::now()))
+        {
+            debug!("failed to touch cache entry {}: {}", path.display(), e);
+        }
+
+        Some(html)
+    }
+
+    /// Store the rendered chapter, failures are only logged as the cache is an optimisation.
+    pub(crate) fn put(&self, key: &str, html: &str) {
+        if let Err(e) = self.write(key, html) {
+            debug!("failed to write cache entry {}: {}", key, e);
+        }
+    }
+
+    fn write(&self, key: &str, html: &str) -> io::Result<()> {
+        let path = self.path(key);
+        fs::create_dir_all(path.parent().unwrap())?;
+
+        // written aside and renamed, so a concurrent build never reads a partial entry.
+        let tmp = path.with_extension(format!(
+            "{}-{}.tmp",
+            std::process::id(),
+            TMP_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp, html)?;
+        fs::rename(&tmp, &path).inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[..2]).join(key).with_extension(EXTENSION)
+    }
+
+    /// Remove the entries not used for `max_age`, or every entry if `None`.
+    pub fn prune(&self, max_age: Option<Duration>) -> io::Result<PruneStats> {
+        let mut stats = PruneStats::default();
+        let now = SystemTime::now();
+
+        let shards = match fs::read_dir(&self.dir) {
+            Ok(shards) => shards,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(stats),
+            Err(e) => return Err(e),
+        };
+
+        for shard in shards {
+            let shard = shard?.path();
+
+            if !shard.is_dir() {
+                continue;
+            }
+
+            for entry in fs::read_dir(&shard)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+
+                let expired = match max_age {
+                    Some(max_age) => metadata
+                        .modified()
+                        .ok()
+                        .and_then(|m| now.duration_since(m).ok())
+                        .is_some_and(|age| age > max_age),
+                    None => true,
+                };
+
+                if metadata.is_file() && expired {
+                    fs::remove_file(entry.path())?;
+                    stats.removed += 1;
+                    stats.freed += metadata.len();
+                }
+            }
+
+            // only succeeds once the shard is empty.
+            let _ = fs::remove_dir(&shard);
+        }
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        error::Error,
+        fs::{self, File},
+        io::{Cursor, Read},
+        path::Path,
+        time::{Duration, SystemTime},
+    };
+
+    use zip::ZipArchive;
+
+    use super::RenderCache;
+    use crate::{
+        cli::{ConvertOpt, OutputFormat},
+        converter::Converter,
+        novel_structure::chapter::{Chapter, Line, LineType, SerChapter},
+        title_format::TitleKind,
+    };
+
+    const NOVEL: &str =
+        "第一章 相遇\n\n正文一。\n\n第二章 别离\n\n正文二。\n\n第三章 重逢\n\n正文三。\n";
+
+    fn chapter(lines: &[&str], offset: u64) -> SerChapter {
+        let mut chapter = Chapter::new(1, 1, 1, "相遇".into(), "第一章 相遇".into(), offset, 1);
+
+        for (i, line) in lines.iter().enumerate() {
+            chapter.content.push(Line {
+                line_type: LineType::Line,
+                content: line.to_string(),
+                offset: offset + i as u64 * 10,
+                line_no: i + 3,
+            });
+        }
+
+        chapter.into()
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut entries = vec![];
+
+        for shard in fs::read_dir(dir).into_iter().flatten() {
+            for entry in fs::read_dir(shard.unwrap().path()).unwrap() {
+                entries.push(entry.unwrap().file_name().to_string_lossy().into_owned());
+            }
+        }
+
+        entries.sort();
+        entries
+    }
+
+    fn build(converter: Converter, text: &str, dir: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut converter = converter
+            .format(OutputFormat::Epub)
+            .cache(Some(RenderCache::new(dir.join("cache"))));
+        let book = converter.parse_bytes(text.as_bytes())?;
+        let path = dir.join("novel.epub");
+        converter.write_to_path(book, &path)?;
+
+        Ok(fs::read(path)?)
+    }
+
+    #[test]
+    fn key() {
+        let cache = RenderCache::new("cache");
+        let opt = ConvertOpt::default();
+        let key = cache.key("chapter", &chapter(&["正文。"], 0), &opt);
+
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, cache.key("chapter", &chapter(&["正文。"], 0), &opt));
+        // moved by an edit earlier in the book.
+        assert_eq!(key, cache.key("chapter", &chapter(&["正文。"], 120), &opt));
+
+        assert_ne!(
+            key,
+            cache.key("chapter_body", &chapter(&["正文。"], 0), &opt)
+        );
+        assert_ne!(key, cache.key("chapter", &chapter(&["正文！"], 0), &opt));
+
+        let kepub = ConvertOpt {
+            format: OutputFormat::Kepub,
+            ..Default::default()
+        };
+        assert_ne!(key, cache.key("chapter", &chapter(&["正文。"], 0), &kepub));
+    }
+
+    #[test]
+    fn hit_and_miss() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("txt2epub-cache-{}", std::process::id()));
+        let cache = dir.join("cache");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        let uncached = {
+            let mut converter = Converter::new().format(OutputFormat::Epub);
+            let book = converter.parse_bytes(NOVEL.as_bytes())?;
+            let mut out = vec![];
+            converter.write(book, &mut out)?;
+            out
+        };
+
+        let first = build(Converter::new(), NOVEL, &dir)?;
+        let rendered = entries(&cache);
+        assert_eq!(rendered.len(), 3);
+        assert_eq!(first, uncached);
+
+        // every chapter is read back, nothing new is rendered.
+        assert_eq!(build(Converter::new(), NOVEL, &dir)?, first);
+        assert_eq!(entries(&cache), rendered);
+
+        let edited = NOVEL.replace("正文二。", "正文二，改过了。");
+        build(Converter::new(), &edited, &dir)?;
+        let after = entries(&cache);
+        assert_eq!(after.len(), 4);
+        assert!(rendered.iter().all(|e| after.contains(e)));
+
+        let header = "第{no}回".parse()?;
+        build(
+            Converter::new().title_format(TitleKind::ChapterHeader, header),
+            NOVEL,
+            &dir,
+        )?;
+        assert_eq!(entries(&cache).len(), 7);
+
+        // only the `<title>` of the pages changes.
+        let global = "{id:arabic} {title}".parse()?;
+        let retitled = build(
+            Converter::new().title_format(TitleKind::Global, global),
+            NOVEL,
+            &dir,
+        )?;
+        assert_eq!(entries(&cache).len(), 10);
+        let mut zip = ZipArchive::new(Cursor::new(retitled))?;
+        let pages = (0..zip.len())
+            .map(|i| {
+                let mut page = String::new();
+                zip.by_index(i)?.read_to_string(&mut page).ok();
+                Ok(page)
+            })
+            .collect::<Result<Vec<_>, zip::result::ZipError>>()?;
+        assert!(pages.iter().any(|p| p.contains("<title>2 别离</title>")));
+
+        fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn prune() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("txt2epub-prune-{}", std::process::id()));
+        let cache = RenderCache::new(&dir);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(cache.prune(None)?.removed, 0);
+
+        let opt = ConvertOpt::default();
+        let (old, used, new) = (
+            cache.key("chapter", &chapter(&["一。"], 0), &opt),
+            cache.key("chapter", &chapter(&["二。"], 0), &opt),
+            cache.key("chapter", &chapter(&["三。"], 0), &opt),
+        );
+
+        for key in [&old, &used, &new] {
+            cache.put(key, "<p>html</p>");
+        }
+
+        let day_ago = SystemTime::now() - Duration::from_secs(24 * 3600);
+
+        for key in [&old, &used] {
+            File::options()
+                .append(true)
+                .open(cache.path(key))?
+                .set_modified(day_ago)?;
+        }
+
+        // a hit refreshes the entry.
+        assert_eq!(cache.get(&used).as_deref(), Some("<p>html</p>"));
+
+        let stats = cache.prune(Some(Duration::from_secs(3600)))?;
+        assert_eq!(stats.removed, 1);
+        assert_eq!(stats.freed, 11);
+        assert!(cache.get(&old).is_none());
+        assert!(cache.get(&new).is_some());
+
+        let stats = cache.prune(None)?;
+        assert_eq!(stats.removed, 2);
+        assert!(entries(&dir).is_empty());
+
+        fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+}
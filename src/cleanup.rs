@@ -0,0 +1,323 @@
+//! Removes the ads, watermarks and boilerplate of scraped files from the chapter content, enabled
+//! by `--clean`.
+//!
+//! A line is removed if it matches one of [`BUILTIN_PATTERNS`] or of the patterns added by
+//! `--clean-pattern`, or if it is repeated at the start or the end of many chapters, like the
+//! footer a site appends to every chapter. The removed lines are listed in the conversion result.
+
+use std::collections::{HashMap, HashSet};
+
+use lazy_static::lazy_static;
+use log::info;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::novel_structure::{
+    chapter::{Chapter, LineType},
+    novel::Novel,
+};
+
+/// The lines matching one of these are ads of the site the text comes from. The words a
+/// sentence may use too only match a whole line without sentence punctuation or quotes.
+pub const BUILTIN_PATTERNS: &[&str] = &[
+    r"(?i)https?://",
+    r"(?i)\bwww\.",
+    r"(?i)^[^，。！？；“”「」]*[\w-]\.(com|net|org|cc)\b[^，。！？；“”「」]*$",
+    "本章未完",
+    "点击下一页",
+    "请记住本站",
+    "本站域名",
+    "天才一秒记住",
+    r"^[^，。！？；“”「」]*最新章节[^，。！？；“”「」]*$",
+    "手机阅读",
+    "手机用户请",
+    "无弹窗",
+    "更新最快",
+    "笔趣阁",
+    "顶点小说",
+    "求月票",
+    "求推荐票",
+    "求收藏",
+    "求订阅",
+];
+
+/// Lines in at least this many chapters are boilerplate by default.
+pub const DEFAULT_MIN_REPEATS: usize = 5;
+/// A repeated line also has to be in one chapter out of this many.
+const REPEAT_RATIO: usize = 10;
+/// Only this many lines at the start and at the end of a chapter can be repeated boilerplate.
+const EDGE_LINES: usize = 3;
+/// Shorter repeated lines, such as `好。`, are most likely part of the story.
+const MIN_REPEATED_LEN: usize = 6;
+/// Dialogue is never boilerplate.
+const QUOTES: &[char] = &['“', '「', '『', '"', '‘'];
+
+lazy_static! {
+    static ref BUILTIN: Vec<Regex> = BUILTIN_PATTERNS
+        .iter()
+        .map(|p| Regex::new(p).unwrap())
+        .collect();
+}
+
+#[derive(Debug, Clone)]
+pub struct CleanOpt {
+    pub enabled: bool,
+    /// removed on top of [`BUILTIN_PATTERNS`].
+    pub patterns: Vec<Regex>,
+    /// lines in at least this many chapters are removed, 0 to keep them.
+    pub min_repeats: usize,
+}
+
+impl Default for CleanOpt {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            patterns: vec![],
+            min_repeats: DEFAULT_MIN_REPEATS,
+        }
+    }
+}
+
+/// A removed line, with the line numbers of each of its occurrences.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Removed {
+    pub content: String,
+    /// the pattern it matched, or the number of chapters it is in.
+    pub reason: String,
+    pub lines: Vec<usize>,
+}
+
+/// Whether the line looks like an ad, by the built-in patterns.
+pub(crate) fn is_ad(line: &str) -> bool {
+    BUILTIN.iter().any(|r| r.is_match(line))
+}
+
+/// Remove the lines matching the patterns from the chapter.
+pub(crate) fn clean_chapter(chapter: &mut Chapter, opt: &CleanOpt, removed: &mut Vec<Removed>) {
+    chapter.content.retain(|line| {
+        if !matches!(line.line_type, LineType::Line) {
+            return true;
+        }
+
+        let Some(pattern) = BUILTIN
+            .iter()
+            .chain(&opt.patterns)
+            .find(|r| r.is_match(&line.content))
+        else {
+            return true;
+        };
+
+        record(removed, &line.content, pattern.as_str(), line.line_no);
+        false
+    });
+}
+
+/// Remove the lines matching the patterns and the lines repeated in many chapters.
+pub(crate) fn clean_novel(novel: &mut Novel, opt: &CleanOpt) -> Vec<Removed> {
+    let mut removed = vec![];
+
+    for chapter in novel.parts.iter_mut().flat_map(|p| &mut p.chapters) {
+        clean_chapter(chapter, opt, &mut removed);
+    }
+
+    let repeated = repeated_lines(novel, opt.min_repeats);
+
+    for chapter in novel.parts.iter_mut().flat_map(|p| &mut p.chapters) {
+        let edges = edge_lines(chapter);
+        let mut index = 0;
+
+        chapter.content.retain(|line| {
+            index += 1;
+
+            if !edges.contains(&(index - 1)) {
+                return true;
+            }
+
+            let Some(chapters) = repeated.get(&line.content) else {
+                return true;
+            };
+
+            let reason = format!("repeated in {} chapters", chapters);
+            record(&mut removed, &line.content, &reason, line.line_no);
+            false
+        });
+    }
+
+    if !removed.is_empty() {
+        info!(
+            "removed {} lines, {} distinct.",
+            removed.iter().map(|r| r.lines.len()).sum::<usize>(),
+            removed.len()
+        );
+    }
+
+    removed
+}
+
+/// The lines at the start or the end of at least `min_repeats` chapters and a tenth of them, with
+/// the number of chapters they are in.
+fn repeated_lines(novel: &Novel, min_repeats: usize) -> HashMap<String, usize> {
+    if min_repeats == 0 {
+        return HashMap::new();
+    }
+
+    let chapters = novel.parts.iter().flat_map(|p| &p.chapters);
+    let threshold = min_repeats.max(chapters.clone().count().div_ceil(REPEAT_RATIO));
+    let mut counts = HashMap::<&str, usize>::new();
+
+    for chapter in chapters {
+        let lines = edge_lines(chapter)
+            .into_iter()
+            .map(|i| chapter.content[i].content.as_str())
+            .collect::<HashSet<_>>();
+
+        for line in lines {
+            *counts.entry(line).or_default() += 1;
+        }
+    }
+
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count >= threshold)
+        .map(|(line, count)| (line.to_string(), count))
+        .collect()
+}
+
+/// The indices of the lines near the start or the end of the chapter which may be boilerplate:
+/// long enough, with a letter, and not dialogue. Lines like `……` or `“嗯。”` are left alone.
+fn edge_lines(chapter: &Chapter) -> HashSet<usize> {
+    let lines = chapter
+        .content
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| matches!(l.line_type, LineType::Line))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    let edge = EDGE_LINES.min(lines.len());
+
+    lines[..edge]
+        .iter()
+        .chain(&lines[lines.len() - edge..])
+        .copied()
+        .filter(|&i| {
+            let line = &chapter.content[i].content;
+
+            line.chars().count() >= MIN_REPEATED_LEN
+                && line.chars().any(char::is_alphanumeric)
+                && !line.starts_with(QUOTES)
+        })
+        .collect()
+}
+
+fn record(removed: &mut Vec<Removed>, content: &str, reason: &str, line: usize) {
+    match removed.iter_mut().find(|r| r.content == content) {
+        Some(r) => r.lines.push(line),
+        None => removed.push(Removed {
+            content: content.to_string(),
+            reason: reason.to_string(),
+            lines: vec![line],
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::{clean_novel, is_ad, CleanOpt};
+    use crate::novel_structure::{
+        chapter::{Chapter, Line, LineType},
+        novel::Novel,
+        part::Part,
+    };
+
+    #[test]
+    fn clean() {
+        let mut part = Part::new(0, String::new(), String::new(), 0, 0);
+        let mut line_no = 0;
+
+        for no in 1..=6 {
+            let mut chapter = Chapter::new(no, no, 0, String::new(), String::new(), 0, 0);
+
+            for content in [
+                "“嗯。”",
+                "好。",
+                "本章未完，请点击下一页继续阅读",
+                "她笑了笑。",
+                "他推开门，屋里一个人也没有。",
+                "……",
+                "正文。",
+                "PS：多谢大家的支持。",
+                "本书首发于某某网站，欢迎收藏。",
+            ] {
+                line_no += 1;
+                chapter.content.push(Line {
+                    line_type: LineType::Line,
+                    content: content.into(),
+                    offset: 0,
+                    line_no,
+                });
+            }
+
+            part.chapters.push(chapter);
+        }
+
+        part.chapters[0].content[0].content = "广告 toy".into();
+        part.chapters[0].content[8].content = "完。".into();
+
+        let mut novel = Novel::new();
+        novel.parts.push(part);
+
+        let opt = CleanOpt {
+            enabled: true,
+            patterns: vec![Regex::new("广告").unwrap()],
+            ..Default::default()
+        };
+        let removed = clean_novel(&mut novel, &opt);
+
+        assert_eq!(removed.len(), 4);
+        assert_eq!(removed[0].lines, [1]);
+        assert_eq!(removed[1].lines, [3, 12, 21, 30, 39, 48]);
+        assert_eq!(removed[2].content, "PS：多谢大家的支持。");
+        assert_eq!(removed[2].reason, "repeated in 6 chapters");
+        assert_eq!(removed[3].reason, "repeated in 5 chapters");
+
+        let content = |chapter: usize| {
+            novel.parts[0].chapters[chapter]
+                .content
+                .iter()
+                .map(|l| l.content.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            content(0),
+            [
+                "好。",
+                "她笑了笑。",
+                "他推开门，屋里一个人也没有。",
+                "……",
+                "正文。",
+                "完。"
+            ]
+        );
+        assert_eq!(
+            content(1),
+            [
+                "“嗯。”",
+                "好。",
+                "她笑了笑。",
+                "他推开门，屋里一个人也没有。",
+                "……",
+                "正文。"
+            ]
+        );
+    }
+
+    #[test]
+    fn whole_line_patterns() {
+        assert!(is_ad("笔下文学最新章节"));
+        assert!(is_ad("biquge.com"));
+        assert!(!is_ad("他一口气读完了最新章节。"));
+        assert!(!is_ad("“去google.com查一下，”他说。"));
+    }
+}
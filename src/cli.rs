@@ -1,11 +1,27 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use log::warn;
 use regex::Regex;
+use serde::Serialize;
+
+use crate::{
+    cache::RenderCache,
+    cleanup::{CleanOpt, Removed, DEFAULT_MIN_REPEATS},
+    heading::{check_groups, ChapterPattern, Guard, HeadingGuards, DEFAULT_MAX_HEADING_LEN},
+    lint::{LintOpt, Rule, Severity},
+    locale::Locale,
+    novel_structure::METADATA_KEYS,
+    title_format::{TitleFormat, TitleFormats, TitleKind},
+};
 
 #[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 /// Convert TXT file to Epub
 pub struct CLIOptions {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     #[clap(index = 1)]
     /// The Files those need to be convert into epub
     pub files: Vec<String>,
@@ -14,13 +30,14 @@ pub struct CLIOptions {
     /// Output directory
     pub out_dir: Option<PathBuf>,
 
-    #[clap(value_parser = parse_regex, short, long)]
-    /// The regex to match part title, at least one capture group needed.
-    pub part_regex: Option<Regex>,
+    #[command(flatten)]
+    pub heading: HeadingArgs,
 
-    #[clap(value_parser = parse_regex, short, long)]
-    /// The regex to match chapter title, at least one capture group needed.
-    pub chapter_regex: Option<Regex>,
+    #[command(flatten)]
+    pub guard: GuardArgs,
+
+    #[command(flatten)]
+    pub clean: CleanArgs,
 
     #[clap(long)]
     /// global replace “ -> 「, ” -> 」, ‘ -> 『, ’ -> 』.
@@ -33,11 +50,328 @@ pub struct CLIOptions {
     #[clap(long)]
     /// the string that treated to be a divider.
     pub divider: Vec<String>,
+
+    #[clap(value_enum, short, long, default_value_t = OutputFormat::Epub)]
+    /// Output format
+    pub format: OutputFormat,
+
+    #[clap(value_enum, long, default_value_t = EpubVersion::V3)]
+    /// EPUB version of the epub and kepub output
+    pub epub_version: EpubVersion,
+
+    #[clap(long, overrides_with = "keep_going")]
+    /// Stop converting the remaining files once one fails
+    pub fail_fast: bool,
+
+    #[clap(long, overrides_with = "fail_fast")]
+    /// Convert every file even if some fail, this is the default. The last of `--fail-fast` and
+    /// `--keep-going` wins
+    pub keep_going: bool,
+
+    #[clap(long)]
+    /// Write a json report of every converted file
+    pub report: Option<PathBuf>,
+
+    #[clap(long)]
+    /// Validate every epub and kepub output, a file fails if its output is invalid
+    pub validate: bool,
+
+    #[clap(long)]
+    /// Parse and write the epub, kepub and txt output in one pass with bounded memory, for huge files
+    pub stream: bool,
+
+    #[clap(long, value_parser = parse_meta, value_name = "KEY=VALUE")]
+    /// Set a metadata field, e.g. `--meta 作者=someone`, over the header and the sidecar files
+    pub meta: Vec<(String, String)>,
+
+    #[clap(long, value_parser = parse_title_format, value_name = "KIND=FORMAT")]
+    /// Format of the chapter, part, chapter-header, part-header or global titles, e.g.
+    /// `--title-format 'chapter=Chapter {no}: {title}'`
+    pub title_format: Vec<(TitleKind, TitleFormat)>,
+
+    #[clap(value_enum, long, default_value_t = HeadingStyle::Normalized)]
+    /// How the headings are written
+    pub heading_style: HeadingStyle,
+
+    #[clap(long)]
+    /// Render every chapter again instead of reusing the ones cached by previous builds
+    pub no_cache: bool,
+
+    #[clap(long, value_name = "DIR")]
+    /// Where rendered chapters are cached, `$XDG_CACHE_HOME/txt2epub` by default
+    pub cache_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub lint: LintArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Check the structure of the files without converting
+    Lint(LintCommand),
+    /// Validate epub files
+    Validate(ValidateCommand),
+    /// Remove the rendered chapters not used by recent builds from the cache
+    CachePrune(CachePruneCommand),
+}
+
+#[derive(Debug, Args)]
+pub struct CachePruneCommand {
+    #[clap(long, value_name = "DIR")]
+    /// The cache directory, `$XDG_CACHE_HOME/txt2epub` by default
+    pub cache_dir: Option<PathBuf>,
+
+    #[clap(long, value_name = "DAYS", default_value_t = 30)]
+    /// Remove the chapters not used for this many days
+    pub older_than: u64,
+
+    #[clap(long, conflicts_with = "older_than")]
+    /// Remove every cached chapter
+    pub all: bool,
+}
+
+impl CachePruneCommand {
+    pub fn cache(&self) -> RenderCache {
+        RenderCache::new(
+            self.cache_dir
+                .clone()
+                .unwrap_or_else(RenderCache::default_dir),
+        )
+    }
+
+    /// `None` to remove everything.
+    pub fn max_age(&self) -> Option<Duration> {
+        (!self.all).then(|| Duration::from_secs(self.older_than * 24 * 60 * 60))
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ValidateCommand {
+    #[clap(index = 1, required = true)]
+    /// The epub files to validate
+    pub files: Vec<String>,
+}
+
+impl From<ValidateCommand> for Vec<PathBuf> {
+    fn from(value: ValidateCommand) -> Self {
+        expand_files(value.files).collect()
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct LintCommand {
+    #[clap(index = 1, required = true)]
+    /// The Files those need to be checked
+    pub files: Vec<String>,
+
+    #[command(flatten)]
+    pub heading: HeadingArgs,
+
+    #[command(flatten)]
+    pub guard: GuardArgs,
+
+    #[command(flatten)]
+    pub lint: LintArgs,
+}
+
+/// The options matching the headings, shared by the conversion and `lint`.
+#[derive(Debug, Clone, Args)]
+pub struct HeadingArgs {
+    #[clap(value_parser = parse_regex, short, long)]
+    /// The regex to match part title, its first group or a `(?P<title>…)` group is the title and a
+    /// `(?P<no>…)` group the number
+    pub part_regex: Option<Regex>,
+
+    #[clap(value_parser = parse_chapter_pattern, short, long, value_name = "[NAME[:KIND]=]REGEX")]
+    /// The regex to match chapter title, its first group or a `(?P<title>…)` group is the title, a
+    /// `(?P<no>…)` group the number and a `(?P<kind>…)` group the kind. Repeat it for files
+    /// mixing heading styles, the first matching one wins. KIND is chapter, prologue, interlude,
+    /// epilogue, extra or afterword
+    pub chapter_regex: Vec<ChapterPattern>,
+
+    #[clap(long, value_parser = parse_lang)]
+    /// Language of the headings and labels: zh-Hans, zh-Hant, ja or en, the metadata language by
+    /// default. Also picks the default part and chapter regex
+    pub lang: Option<Locale>,
+}
+
+impl HeadingArgs {
+    /// The part regex and the chapter patterns given, or the defaults of `--lang`.
+    fn patterns(self) -> (Regex, Vec<ChapterPattern>) {
+        let defaults = self.lang.unwrap_or_default();
+        let part_regex = self
+            .part_regex
+            .unwrap_or_else(|| Regex::new(defaults.part_regex()).unwrap());
+
+        (part_regex, chapter_patterns(self.chapter_regex, self.lang))
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct GuardArgs {
+    #[clap(value_enum, long, value_name = "GUARD", value_delimiter = ',')]
+    /// Reject the part and chapter headings failing the guard, the rejected lines are logged at
+    /// debug level
+    pub guard: Vec<Guard>,
+
+    #[clap(long, value_name = "CHARS", default_value_t = DEFAULT_MAX_HEADING_LEN)]
+    /// The longest heading with `--guard max-len`
+    pub max_heading_len: usize,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct CleanArgs {
+    #[clap(long)]
+    /// Remove the ad, watermark and boilerplate lines from the chapters, they are listed in the
+    /// report
+    pub clean: bool,
+
+    #[clap(long, value_parser = parse_clean_pattern, value_name = "REGEX")]
+    /// Remove the lines matching the regex too, implies `--clean`
+    pub clean_pattern: Vec<Regex>,
+
+    #[clap(long, value_name = "CHAPTERS", default_value_t = DEFAULT_MIN_REPEATS)]
+    /// Remove the lines repeated in at least this many chapters, and a tenth of them, with
+    /// `--clean`, 0 to keep them
+    pub min_repeats: usize,
+}
+
+impl From<CleanArgs> for CleanOpt {
+    fn from(value: CleanArgs) -> Self {
+        Self {
+            enabled: value.clean || !value.clean_pattern.is_empty(),
+            patterns: value.clean_pattern,
+            min_repeats: value.min_repeats,
+        }
+    }
+}
+
+fn parse_clean_pattern(s: &str) -> Result<Regex, String> {
+    Regex::new(s).map_err(|_| "Invalid regex".into())
+}
+
+impl From<GuardArgs> for HeadingGuards {
+    fn from(value: GuardArgs) -> Self {
+        Self {
+            guards: value.guard,
+            max_len: value.max_heading_len,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct LintArgs {
+    #[clap(value_enum, long, value_name = "RULE")]
+    /// Do not report the lint rule
+    pub allow: Vec<Rule>,
+
+    #[clap(value_enum, long, value_name = "RULE")]
+    /// Report the lint rule as a warning
+    pub warn: Vec<Rule>,
+
+    #[clap(value_parser = parse_deny, long, value_name = "RULE")]
+    /// Fail on the lint rule, `warnings` to fail on every warning
+    pub deny: Vec<Deny>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Deny {
+    Warnings,
+    Rule(Rule),
+}
+
+fn parse_deny(s: &str) -> Result<Deny, String> {
+    if s == "warnings" {
+        return Ok(Deny::Warnings);
+    }
+
+    Rule::from_str(s, false).map(Deny::Rule)
+}
+
+impl From<&LintArgs> for LintOpt {
+    fn from(value: &LintArgs) -> Self {
+        let mut opt = LintOpt::default();
+
+        for &rule in &value.allow {
+            opt.levels.insert(rule, Severity::Allow);
+        }
+
+        for &rule in &value.warn {
+            opt.levels.insert(rule, Severity::Warn);
+        }
+
+        for &deny in &value.deny {
+            match deny {
+                Deny::Warnings => opt.deny_warnings = true,
+                Deny::Rule(rule) => {
+                    opt.levels.insert(rule, Severity::Deny);
+                }
+            }
+        }
+
+        opt
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EpubVersion {
+    /// EPUB 2, for old readers
+    #[value(name = "2")]
+    V2 = 2,
+    /// EPUB 3
+    #[value(name = "3")]
+    V3 = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum HeadingStyle {
+    /// The heading lines as they are in the source file, e.g. `第一百零八章：决战（上）`
+    Raw,
+    /// Rebuilt from the number and the captured title, e.g. `第一百零八章 决战（上）`
+    #[default]
+    Normalized,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// EPUB 3 book
+    Epub,
+    /// Single self-contained html file with a TOC sidebar
+    Html,
+    /// Static site, one page per chapter
+    Site,
+    /// FictionBook 2
+    Fb2,
+    /// Kobo flavoured EPUB
+    Kepub,
+    /// Normalised plain text
+    Txt,
+    /// The parsed novel as json, see `txt2epub::model`
+    Json,
+    /// The parsed novel as yaml, see `txt2epub::model`
+    Yaml,
+}
+
+impl OutputFormat {
+    /// The output file name for the novel `name`, a directory for `Site`.
+    pub fn file_name(&self, name: &str) -> String {
+        match self {
+            OutputFormat::Epub => format!("{}.epub", name),
+            OutputFormat::Html => format!("{}.html", name),
+            OutputFormat::Site => name.to_string(),
+            OutputFormat::Fb2 => format!("{}.fb2", name),
+            OutputFormat::Kepub => format!("{}.kepub.epub", name),
+            OutputFormat::Txt => format!("{}.clean.txt", name),
+            OutputFormat::Json => format!("{}.json", name),
+            OutputFormat::Yaml => format!("{}.yaml", name),
+        }
+    }
 }
 
 impl CLIOptions {
     pub fn check(self) -> Self {
-        if self.files.is_empty() {
+        if self.command.is_none() && self.files.is_empty() {
             eprintln!("should provide one file at least.");
             std::process::exit(1);
         }
@@ -46,14 +380,73 @@ impl CLIOptions {
     }
 }
 
-fn parse_regex(s: &str) -> Result<Regex, &'static str> {
-    let regex = Regex::new(s).map_err(|_| "Invalid regex")?;
+fn parse_meta(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| "expected KEY=VALUE".to_string())?;
+    let key = key.trim();
+
+    if !METADATA_KEYS.contains(&key) {
+        return Err(format!("unknown metadata key `{}`", key));
+    }
+
+    Ok((key.to_string(), value.trim().to_string()))
+}
 
-    if regex.captures_len() > 1 {
-        Ok(regex)
-    } else {
-        Err("The regex must have one capture group")
+fn parse_lang(s: &str) -> Result<Locale, String> {
+    Locale::from_tag(s).ok_or_else(|| {
+        format!(
+            "unsupported language `{}`, expected zh-Hans, zh-Hant, ja or en",
+            s
+        )
+    })
+}
+
+fn parse_title_format(s: &str) -> Result<(TitleKind, TitleFormat), String> {
+    let (kind, format) = s
+        .split_once('=')
+        .ok_or_else(|| "expected KIND=FORMAT".to_string())?;
+
+    Ok((kind.trim().parse()?, format.parse()?))
+}
+
+fn parse_chapter_pattern(s: &str) -> Result<ChapterPattern, String> {
+    let pattern = s.parse::<ChapterPattern>()?;
+    check_groups(&pattern.regex, true)?;
+
+    Ok(pattern)
+}
+
+/// The patterns given on the command line, the unnamed ones are named by their position.
+fn chapter_patterns(patterns: Vec<ChapterPattern>, lang: Option<Locale>) -> Vec<ChapterPattern> {
+    if patterns.is_empty() {
+        return default_chapter_patterns(lang.unwrap_or_default());
     }
+
+    patterns
+        .into_iter()
+        .enumerate()
+        .map(|(i, mut pattern)| {
+            if pattern.name.is_empty() {
+                pattern.name = format!("chapter-{}", i + 1);
+            }
+            pattern
+        })
+        .collect()
+}
+
+pub(crate) fn default_chapter_patterns(locale: Locale) -> Vec<ChapterPattern> {
+    vec![ChapterPattern::new(
+        DEFAULT_CHAPTER_PATTERN,
+        Regex::new(locale.chapter_regex()).unwrap(),
+    )]
+}
+
+fn parse_regex(s: &str) -> Result<Regex, String> {
+    let regex = Regex::new(s).map_err(|_| "Invalid regex")?;
+    check_groups(&regex, false)?;
+
+    Ok(regex)
 }
 
 impl From<CLIOptions> for Vec<ConvertOpt> {
@@ -61,27 +454,44 @@ impl From<CLIOptions> for Vec<ConvertOpt> {
         let CLIOptions {
             files,
             out_dir,
-            part_regex,
-            chapter_regex,
+            heading,
+            guard,
+            clean,
             replace_quote,
             long_preface,
             divider,
+            format,
+            epub_version,
+            validate,
+            stream,
+            meta,
+            title_format,
+            heading_style,
+            no_cache,
+            cache_dir,
+            lint,
+            ..
         } = value;
 
-        let part_regex = part_regex.unwrap_or_else(|| Regex::new("^第.+[部|卷] (.*)$").unwrap());
-        let chapter_regex = chapter_regex.unwrap_or_else(|| Regex::new("^第.+[章] (.*)$").unwrap());
+        let lang = heading.lang;
+        let (part_regex, chapter_patterns) = heading.patterns();
+        let guards = HeadingGuards::from(guard);
+        let clean = CleanOpt::from(clean);
+        let lint = LintOpt::from(&lint);
+        let mut title_formats = TitleFormats::default();
+        for (kind, format) in title_format {
+            title_formats.set(kind, format);
+        }
+        let cache = (!no_cache)
+            .then(|| RenderCache::new(cache_dir.unwrap_or_else(RenderCache::default_dir)));
 
-        files
-            .into_iter()
-            .filter_map(|p| glob::glob(&p).ok())
-            .flat_map(|p| p.collect::<Vec<_>>())
-            .filter_map(|p| p.ok())
+        expand_files(files)
             .map(|path| {
                 let name = path.file_prefix().unwrap().to_string_lossy().to_string();
                 let out_file = out_dir
                     .clone()
                     .unwrap_or_else(|| path.parent().unwrap().to_path_buf())
-                    .join(format!("{}.epub", name));
+                    .join(format.file_name(&name));
 
                 ConvertOpt {
                     path,
@@ -89,24 +499,208 @@ impl From<CLIOptions> for Vec<ConvertOpt> {
                     out_file,
                     have_section: true,
                     part_regex: part_regex.clone(),
-                    chapter_regex: chapter_regex.clone(),
+                    chapter_patterns: chapter_patterns.clone(),
+                    guards: guards.clone(),
+                    clean: clean.clone(),
                     replace_quote,
                     long_preface,
                     divider: divider.clone(),
+                    format,
+                    epub_version,
+                    validate,
+                    stream,
+                    meta: meta.clone(),
+                    lang,
+                    locale: Locale::default(),
+                    title_formats: title_formats.clone(),
+                    heading_style,
+                    lint: lint.clone(),
+                    cache: cache.clone(),
+                    warnings: vec![],
+                    removed: vec![],
                 }
             })
             .collect()
     }
 }
 
+impl From<LintCommand> for Vec<ConvertOpt> {
+    fn from(value: LintCommand) -> Self {
+        let LintCommand {
+            files,
+            heading,
+            guard,
+            lint,
+        } = value;
+
+        let lang = heading.lang;
+        let (part_regex, chapter_patterns) = heading.patterns();
+        let default = ConvertOpt {
+            part_regex,
+            chapter_patterns,
+            guards: guard.into(),
+            lang,
+            lint: LintOpt::from(&lint),
+            ..Default::default()
+        };
+
+        expand_files(files)
+            .map(|path| ConvertOpt {
+                name: path.file_prefix().unwrap().to_string_lossy().to_string(),
+                path,
+                ..default.clone()
+            })
+            .collect()
+    }
+}
+
+fn expand_files(files: Vec<String>) -> impl Iterator<Item = PathBuf> {
+    files
+        .into_iter()
+        .filter_map(|p| glob::glob(&p).ok())
+        .flat_map(|p| p.collect::<Vec<_>>())
+        .filter_map(|p| p.ok())
+}
+
+pub const DEFAULT_PART_REGEX: &str = "^第.+[部|卷] (.*)$";
+pub const DEFAULT_CHAPTER_REGEX: &str = "^第.+[章] (.*)$";
+/// The name of the chapter pattern used when none is given.
+pub const DEFAULT_CHAPTER_PATTERN: &str = "chapter";
+
+#[derive(Debug, Clone)]
 pub struct ConvertOpt {
     pub path: PathBuf,
     pub name: String,
     pub out_file: PathBuf,
     pub have_section: bool,
     pub part_regex: Regex,
-    pub chapter_regex: Regex,
+    /// tried in order, the first matching one wins.
+    pub chapter_patterns: Vec<ChapterPattern>,
+    /// reject the part and chapter headings looking like content lines.
+    pub guards: HeadingGuards,
+    /// remove the ads and boilerplate from the chapters, see [`crate::cleanup`].
+    pub clean: CleanOpt,
     pub replace_quote: bool,
     pub long_preface: bool,
     pub divider: Vec<String>,
+    pub format: OutputFormat,
+    pub epub_version: EpubVersion,
+    /// validate the epub and kepub output after writing it to a path.
+    pub validate: bool,
+    /// parse and write in one pass, see `Converter::stream_to_path`.
+    pub stream: bool,
+    /// metadata fields set over the header and the sidecar files.
+    pub meta: Vec<(String, String)>,
+    /// the locale of the headings and labels, the metadata language if not set.
+    pub lang: Option<Locale>,
+    /// the locale of the book being written, see [`ConvertOpt::select_locale`].
+    pub(crate) locale: Locale,
+    /// formats of the titles, the locale decides the titles without one.
+    pub title_formats: TitleFormats,
+    pub heading_style: HeadingStyle,
+    pub lint: LintOpt,
+    /// reuse the chapters rendered by previous builds, see [`crate::cache`].
+    pub cache: Option<RenderCache>,
+    /// warnings raised while converting, handed back to the caller with the result.
+    pub warnings: Vec<String>,
+    /// lines removed by the cleanup, handed back to the caller with the result.
+    pub removed: Vec<Removed>,
+}
+
+impl Default for ConvertOpt {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::new(),
+            name: String::new(),
+            out_file: PathBuf::new(),
+            have_section: true,
+            part_regex: Regex::new(DEFAULT_PART_REGEX).unwrap(),
+            chapter_patterns: default_chapter_patterns(Locale::default()),
+            guards: HeadingGuards::default(),
+            clean: CleanOpt::default(),
+            replace_quote: false,
+            long_preface: false,
+            divider: vec![],
+            format: OutputFormat::Epub,
+            epub_version: EpubVersion::V3,
+            validate: false,
+            stream: false,
+            meta: vec![],
+            lang: None,
+            locale: Locale::default(),
+            title_formats: TitleFormats::default(),
+            heading_style: HeadingStyle::default(),
+            lint: LintOpt::default(),
+            cache: None,
+            warnings: vec![],
+            removed: vec![],
+        }
+    }
+}
+
+impl ConvertOpt {
+    /// Log the warning and keep it for the conversion result.
+    pub(crate) fn warn(&mut self, message: impl Into<String>) {
+        let message = message.into();
+        warn!("{}", message);
+        self.warnings.push(message);
+    }
+
+    /// Use `--lang`, or else the language of the book, for the book about to be written.
+    pub(crate) fn select_locale(&mut self, language: Option<&str>) {
+        self.locale = match (self.lang, language) {
+            (Some(lang), _) => lang,
+            (None, Some(language)) => Locale::from_tag(language).unwrap_or_else(|| {
+                self.warn(format!(
+                    "no headings for the language `{}`, the chinese ones are used.",
+                    language
+                ));
+                Locale::default()
+            }),
+            (None, None) => Locale::default(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::{CLIOptions, Command, HeadingArgs};
+
+    #[test]
+    fn last_of_fail_fast_and_keep_going_wins() {
+        let fail_fast = |args: &[&str]| {
+            CLIOptions::parse_from(["txt2epub"].iter().chain(args).chain(&["a.txt"])).fail_fast
+        };
+
+        assert!(!fail_fast(&[]));
+        assert!(fail_fast(&["--fail-fast"]));
+        assert!(!fail_fast(&["--fail-fast", "--keep-going"]));
+        assert!(fail_fast(&["--keep-going", "--fail-fast"]));
+    }
+
+    #[test]
+    fn lint_matches_headings_like_convert() {
+        let patterns = |heading: HeadingArgs| {
+            let (part_regex, chapter_patterns) = heading.patterns();
+            let chapter_regexes = chapter_patterns
+                .iter()
+                .map(|p| p.regex.to_string())
+                .collect::<Vec<_>>();
+
+            (part_regex.to_string(), chapter_regexes)
+        };
+
+        let convert = CLIOptions::parse_from(["txt2epub", "--lang", "en", "a.txt"]).heading;
+        let Some(Command::Lint(lint)) =
+            CLIOptions::parse_from(["txt2epub", "lint", "--lang", "en", "a.txt"]).command
+        else {
+            panic!("expected the lint command");
+        };
+
+        let (part_regex, chapter_regexes) = patterns(convert);
+        assert!(part_regex.contains("part|book|volume"));
+        assert_eq!(patterns(lint.heading), (part_regex, chapter_regexes));
+    }
 }
@@ -0,0 +1,1277 @@
+//! The library entry point.
+//!
+//! ```no_run
+//! use txt2epub::{cli::OutputFormat, converter::Converter};
+//!
+//! let mut converter = Converter::new().format(OutputFormat::Epub).replace_quote(true);
+//!
+//! let mut book = converter.parse_path("novel.txt")?;
+//! book.metadata.author = "someone".into();
+//!
+//! let result = converter.write_to_path(book, "novel.epub")?;
+//! println!("{} chapters, {} bytes", result.stats.chapters, result.stats.output_size);
+//! # Ok::<(), txt2epub::error::Txt2EpubError>(())
+//! ```
+
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Cursor, Read, Seek, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use log::{error, info};
+use regex::Regex;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cache::RenderCache,
+    cleanup::{clean_chapter, CleanOpt, Removed},
+    cli::{ConvertOpt, EpubVersion, HeadingStyle, OutputFormat, DEFAULT_CHAPTER_PATTERN},
+    epub::{self, EpubFactory, EpubStream},
+    error::{Result, Txt2EpubError},
+    fb2::Fb2Builder,
+    heading::{describe, ChapterPattern, HeadingGuards},
+    html::HtmlBuilder,
+    lint::{lint, Issue, Rule, Severity},
+    locale::Locale,
+    model::Book,
+    novel_structure::novel::Novel,
+    parse::{decode_txt, parse_txt},
+    stream::{Event, LineReader, StreamParser},
+    title_format::{TitleFormat, TitleKind},
+    txt::TxtBuilder,
+    validate::validate_path,
+    WriteToEpub, WriteToFb2, WriteToHtml, WriteToTxt,
+};
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConvertStats {
+    pub encoding: String,
+    pub part_regex: String,
+    pub chapter_regex: String,
+    pub parts: usize,
+    pub chapters: usize,
+    /// words of the chapter content, every CJK character counts as a word.
+    pub words: usize,
+    /// characters of the chapter content, headings excluded.
+    pub characters: usize,
+    /// bytes written, summed over all pages for the site output.
+    pub output_size: u64,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConvertResult {
+    pub stats: ConvertStats,
+    pub warnings: Vec<String>,
+    /// lines removed by `--clean`.
+    pub removed: Vec<Removed>,
+    /// the output file, `None` if written to a writer.
+    pub output: Option<PathBuf>,
+    /// sha256 of the output in hex, `None` for the site output.
+    pub checksum: Option<String>,
+}
+
+/// Parses a txt into a [`Book`] and writes it in the configured [`OutputFormat`].
+///
+/// The book can be inspected and modified between [`Converter::parse_path`] and
+/// [`Converter::write`].
+#[derive(Debug, Clone, Default)]
+pub struct Converter {
+    opt: ConvertOpt,
+}
+
+impl From<ConvertOpt> for Converter {
+    fn from(opt: ConvertOpt) -> Self {
+        Self { opt }
+    }
+}
+
+impl Converter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn options(&self) -> &ConvertOpt {
+        &self.opt
+    }
+
+    pub fn options_mut(&mut self) -> &mut ConvertOpt {
+        &mut self.opt
+    }
+
+    /// The name of the novel, used when the source is not a file.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.opt.name = name.into();
+        self
+    }
+
+    /// The regex to match part title, its first capture group is the title.
+    pub fn part_regex(mut self, regex: Regex) -> Self {
+        self.opt.part_regex = regex;
+        self
+    }
+
+    /// The regex to match chapter title, its first capture group is the title.
+    pub fn chapter_regex(mut self, regex: Regex) -> Self {
+        self.opt.chapter_patterns = vec![ChapterPattern::new(DEFAULT_CHAPTER_PATTERN, regex)];
+        self
+    }
+
+    /// The patterns to match chapter titles, tried in order.
+    pub fn chapter_patterns(mut self, patterns: Vec<ChapterPattern>) -> Self {
+        self.opt.chapter_patterns = patterns;
+        self
+    }
+
+    /// Reject the part and chapter headings looking like content lines.
+    pub fn guards(mut self, guards: HeadingGuards) -> Self {
+        self.opt.guards = guards;
+        self
+    }
+
+    /// Remove the ads and boilerplate from the chapters, see [`crate::cleanup`].
+    pub fn clean(mut self, clean: CleanOpt) -> Self {
+        self.opt.clean = clean;
+        self
+    }
+
+    pub fn replace_quote(mut self, replace_quote: bool) -> Self {
+        self.opt.replace_quote = replace_quote;
+        self
+    }
+
+    pub fn long_preface(mut self, long_preface: bool) -> Self {
+        self.opt.long_preface = long_preface;
+        self
+    }
+
+    pub fn divider(mut self, divider: impl Into<String>) -> Self {
+        self.opt.divider.push(divider.into());
+        self
+    }
+
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.opt.format = format;
+        self
+    }
+
+    pub fn epub_version(mut self, version: EpubVersion) -> Self {
+        self.opt.epub_version = version;
+        self
+    }
+
+    /// Validate the epub and kepub output written by [`Converter::write_to_path`].
+    pub fn validate(mut self, validate: bool) -> Self {
+        self.opt.validate = validate;
+        self
+    }
+
+    /// Override the severity of a lint rule checked before writing.
+    pub fn lint_level(mut self, rule: Rule, severity: Severity) -> Self {
+        self.opt.lint.levels.insert(rule, severity);
+        self
+    }
+
+    /// Fail on every lint warning.
+    pub fn deny_warnings(mut self, deny_warnings: bool) -> Self {
+        self.opt.lint.deny_warnings = deny_warnings;
+        self
+    }
+
+    /// Parse and write huge files in one pass, see [`Converter::stream_to_path`].
+    pub fn stream(mut self, stream: bool) -> Self {
+        self.opt.stream = stream;
+        self
+    }
+
+    /// Set a metadata field over the header and the sidecar files, `key` is a header key such as
+    /// `author` or `作者`.
+    pub fn meta(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.opt.meta.push((key.into(), value.into()));
+        self
+    }
+
+    /// The locale of the headings and labels, the metadata language if `None`.
+    ///
+    /// Unlike `--lang`, the part and chapter regex are left as they are.
+    pub fn lang(mut self, lang: Option<Locale>) -> Self {
+        self.opt.lang = lang;
+        self
+    }
+
+    /// Format the titles of `kind`, see [`crate::title_format`].
+    pub fn title_format(mut self, kind: TitleKind, format: TitleFormat) -> Self {
+        self.opt.title_formats.set(kind, format);
+        self
+    }
+
+    /// Keep the heading lines as they are in the source file, or rebuild them.
+    pub fn heading_style(mut self, style: HeadingStyle) -> Self {
+        self.opt.heading_style = style;
+        self
+    }
+
+    /// Reuse the chapters rendered by previous builds, `None` renders every chapter.
+    pub fn cache(mut self, cache: Option<RenderCache>) -> Self {
+        self.opt.cache = cache;
+        self
+    }
+
+    pub fn parse_path(&mut self, path: impl AsRef<Path>) -> Result<Book> {
+        let path = path.as_ref();
+
+        self.set_path(path);
+        self.parse_bytes(&fs::read(path).map_err(|e| Txt2EpubError::io(path, e))?)
+    }
+
+    pub fn parse_reader<R: Read + Seek>(&mut self, mut reader: R) -> Result<Book> {
+        let mut bytes = vec![];
+
+        reader.rewind()?;
+        reader.read_to_end(&mut bytes)?;
+
+        self.parse_bytes(&bytes)
+    }
+
+    pub fn parse_bytes(&mut self, bytes: &[u8]) -> Result<Book> {
+        let (mut file, encoding) = decode_txt(bytes, &mut self.opt)?;
+
+        let novel = parse_txt(&mut file, &mut self.opt)?;
+
+        Ok(Book::new(
+            novel,
+            self.opt.path.display().to_string(),
+            encoding.name().to_string(),
+            self.opt.have_section,
+        ))
+    }
+
+    /// Check the structure of the book, allowed rules are left out.
+    pub fn lint(&self, book: &Book) -> Vec<Issue> {
+        lint(book, &self.opt.lint)
+    }
+
+    /// Write the book to `out`, the site output can only be written with [`Converter::write_to_path`].
+    ///
+    /// The epub is assembled in memory before it is written, [`Converter::write_to_path`] writes
+    /// it into the file directly.
+    pub fn write<W: Write>(&mut self, book: Book, out: W) -> Result<ConvertResult> {
+        self.check(&book)?;
+        self.write_checked(book, out)
+    }
+
+    /// Write the book to the file at `path`, or into the directory at `path` for the site output.
+    ///
+    /// The book is linted before anything is created, a denied issue leaves no output behind.
+    pub fn write_to_path(&mut self, book: Book, path: impl AsRef<Path>) -> Result<ConvertResult> {
+        let path = path.as_ref();
+
+        self.check(&book)?;
+
+        if matches!(self.opt.format, OutputFormat::Epub | OutputFormat::Kepub) {
+            return self.write_epub_to_path(book, path);
+        }
+
+        if self.opt.format != OutputFormat::Site {
+            let file = File::create(path).map_err(|e| Txt2EpubError::io(path, e))?;
+            let mut result = self.write_checked(book, file)?;
+
+            result.output = Some(path.to_path_buf());
+
+            self.validate_output(path)?;
+
+            return Ok(result);
+        }
+
+        let start = Instant::now();
+        let mut stats = self.stats(&book);
+        let mut html = HtmlBuilder::new();
+
+        self.opt.have_section = book.has_parts;
+        self.opt.select_locale(book.metadata.language.as_deref());
+
+        stats.output_size = Novel::from(book)
+            .write_to_html(&mut html, &mut self.opt)?
+            .generate_site(path)?;
+        stats.elapsed = start.elapsed();
+
+        let mut result = self.finish(stats);
+        result.output = Some(path.to_path_buf());
+
+        Ok(result)
+    }
+
+    /// Convert `ConvertOpt::path` into `ConvertOpt::out_file`.
+    pub fn convert_file(&mut self) -> Result<ConvertResult> {
+        info!("converting `{}`.", self.opt.path.display());
+
+        let start = Instant::now();
+        let path = self.opt.path.clone();
+        let out_file = self.opt.out_file.clone();
+
+        let streamable = matches!(
+            self.opt.format,
+            OutputFormat::Epub | OutputFormat::Kepub | OutputFormat::Txt
+        );
+
+        if self.opt.stream && !streamable {
+            self.opt.warn(format!(
+                "the {:?} output can not be streamed, reading the whole file.",
+                self.opt.format
+            ));
+        }
+
+        let mut result = if self.opt.stream && streamable {
+            self.stream_to_path(&path, &out_file)?
+        } else {
+            let book = self.parse_path(&path)?;
+            self.write_to_path(book, &out_file)?
+        };
+
+        result.stats.elapsed = start.elapsed();
+
+        info!("saving file to {}", out_file.display());
+        info!("finish converting {}.", path.display());
+        info!("cost {}s.\n", result.stats.elapsed.as_secs_f32());
+
+        Ok(result)
+    }
+
+    /// Convert the file at `path` into `out_path` in one pass, keeping only one chapter and the
+    /// table of contents in memory.
+    ///
+    /// Only the epub, kepub and txt output can be streamed. The lint checks are skipped as they
+    /// need the whole book, and chapters before the first part title are kept in a part without
+    /// title instead of being dropped.
+    pub fn stream_to_path(
+        &mut self,
+        path: impl AsRef<Path>,
+        out_path: impl AsRef<Path>,
+    ) -> Result<ConvertResult> {
+        let (path, out_path) = (path.as_ref(), out_path.as_ref());
+        let start = Instant::now();
+
+        self.set_path(path);
+
+        let file = File::open(path).map_err(|e| Txt2EpubError::io(path, e))?;
+        let lines =
+            LineReader::new(BufReader::new(file)).map_err(|e| Txt2EpubError::io(path, e))?;
+
+        // the parser keeps its own options, the writers need them mutable.
+        let parse_opt = self.opt.clone();
+        let (mut parser, metadata) = StreamParser::new(lines, &parse_opt)?;
+
+        let out = File::create(out_path).map_err(|e| Txt2EpubError::io(out_path, e))?;
+        let mut out = BufWriter::new(out);
+        let mut stats = ConvertStats {
+            part_regex: self.opt.part_regex.to_string(),
+            chapter_regex: describe(&self.opt.chapter_patterns),
+            ..Default::default()
+        };
+
+        let opt = &mut self.opt;
+        opt.select_locale(metadata.language());
+
+        if !opt.guards.guards.is_empty() {
+            opt.warn("the heading guards are not applied when streaming.");
+        }
+
+        if opt.clean.enabled && opt.clean.min_repeats > 0 {
+            opt.warn("the repeated lines are not removed when streaming, only the patterns.");
+        }
+
+        match opt.format {
+            OutputFormat::Epub | OutputFormat::Kepub => {
+                let mut epub = EpubStream::new(&mut out, opt.epub_version)?;
+
+                let package = metadata.package_metadata();
+                metadata.write_to_epub(&mut epub, opt)?;
+
+                for event in &mut parser {
+                    match stream_event(event?, &mut stats, opt) {
+                        Event::Part(part) => part.write_to_epub(&mut epub, opt)?,
+                        Event::Chapter(chapter) => chapter.write_to_epub(&mut epub, opt)?,
+                    };
+                }
+
+                epub.finish(&package)?;
+            }
+            OutputFormat::Txt => {
+                let mut txt = TxtBuilder::new();
+
+                metadata.write_to_txt(&mut txt, opt)?;
+
+                for event in &mut parser {
+                    match stream_event(event?, &mut stats, opt) {
+                        Event::Part(part) => part.write_to_txt(&mut txt, opt)?,
+                        Event::Chapter(chapter) => chapter.write_to_txt(&mut txt, opt)?,
+                    };
+
+                    txt.flush_to(&mut out)?;
+                }
+
+                txt.flush_to(&mut out)?;
+            }
+            format => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("the {:?} output can not be streamed", format),
+                )
+                .into());
+            }
+        }
+
+        parser.lines().finish(opt)?;
+        out.flush()?;
+        drop(out);
+
+        // the zip writer seeks back to complete the entries, so the file is hashed once written.
+        let (size, checksum) = file_digest(out_path)?;
+
+        stats.encoding = parser.lines().encoding().name().to_string();
+        stats.output_size = size;
+        stats.elapsed = start.elapsed();
+
+        let mut result = self.finish(stats);
+        result.checksum = Some(checksum);
+        result.output = Some(out_path.to_path_buf());
+
+        self.validate_output(out_path)?;
+
+        Ok(result)
+    }
+
+    fn set_path(&mut self, path: &Path) {
+        self.opt.path = path.to_path_buf();
+
+        if self.opt.name.is_empty() {
+            if let Some(name) = path.file_prefix() {
+                self.opt.name = name.to_string_lossy().to_string();
+            }
+        }
+    }
+
+    /// Validate the epub and kepub output at `path` if enabled.
+    fn validate_output(&self, path: &Path) -> Result<()> {
+        if !self.opt.validate
+            || !matches!(self.opt.format, OutputFormat::Epub | OutputFormat::Kepub)
+        {
+            return Ok(());
+        }
+
+        let problems = validate_path(path)?;
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Txt2EpubError::Validate {
+                path: path.to_path_buf(),
+                problems,
+            })
+        }
+    }
+
+    /// Write the epub into the file at `path` as the archive is completed.
+    fn write_epub_to_path(&mut self, book: Book, path: &Path) -> Result<ConvertResult> {
+        let start = Instant::now();
+        let mut stats = self.stats(&book);
+
+        self.opt.have_section = book.has_parts;
+        self.opt.select_locale(book.metadata.language.as_deref());
+
+        let file = File::create(path).map_err(|e| Txt2EpubError::io(path, e))?;
+        write_epub(book, &mut self.opt, BufWriter::new(file))?.flush()?;
+
+        // the zip writer seeks back to complete the entries, so the file is hashed once written.
+        let (size, checksum) = file_digest(path)?;
+
+        stats.output_size = size;
+        stats.elapsed = start.elapsed();
+
+        let mut result = self.finish(stats);
+        result.checksum = Some(checksum);
+        result.output = Some(path.to_path_buf());
+
+        self.validate_output(path)?;
+
+        Ok(result)
+    }
+
+    fn write_checked<W: Write>(&mut self, book: Book, out: W) -> Result<ConvertResult> {
+        let start = Instant::now();
+        let mut stats = self.stats(&book);
+        let mut out = CountingWriter::new(out);
+
+        self.write_book(book, &mut out)?;
+
+        stats.output_size = out.count;
+        stats.elapsed = start.elapsed();
+
+        let mut result = self.finish(stats);
+        result.checksum = Some(format!("{:x}", out.hasher.finalize()));
+
+        Ok(result)
+    }
+
+    fn write_book<W: Write>(&mut self, book: Book, mut out: W) -> Result<()> {
+        let opt = &mut self.opt;
+
+        opt.have_section = book.has_parts;
+        opt.select_locale(book.metadata.language.as_deref());
+
+        match opt.format {
+            OutputFormat::Epub | OutputFormat::Kepub => {
+                // the zip writer needs to seek, `write_epub_to_path` writes into the file instead.
+                let epub = write_epub(book, opt, Cursor::new(vec![]))?;
+                out.write_all(epub.get_ref())?;
+            }
+            OutputFormat::Html => {
+                let mut html = HtmlBuilder::new();
+
+                Novel::from(book)
+                    .write_to_html(&mut html, opt)?
+                    .generate(out)?;
+            }
+            OutputFormat::Site => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "the site output is a directory, write it with `write_to_path`",
+                )
+                .into());
+            }
+            OutputFormat::Fb2 => {
+                let mut fb2 = Fb2Builder::new(&opt.name);
+
+                Novel::from(book)
+                    .write_to_fb2(&mut fb2, opt)?
+                    .generate(out)?;
+            }
+            OutputFormat::Txt => {
+                let mut txt = TxtBuilder::new();
+
+                Novel::from(book)
+                    .write_to_txt(&mut txt, opt)?
+                    .generate(out)?;
+            }
+            OutputFormat::Json => serde_json::to_writer_pretty(out, &book)?,
+            OutputFormat::Yaml => {
+                serde_yaml::to_writer(out, &book).map_err(std::io::Error::other)?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lint the book before writing, warnings are kept and denied issues fail the conversion.
+    fn check(&mut self, book: &Book) -> Result<()> {
+        let path = self.opt.path.clone();
+        let mut denied = vec![];
+
+        for issue in self.lint(book) {
+            if issue.severity == Severity::Deny {
+                error!("{}:{}", path.display(), issue);
+                denied.push(issue);
+            } else {
+                self.opt.warn(format!("{}:{}", path.display(), issue));
+            }
+        }
+
+        if denied.is_empty() {
+            Ok(())
+        } else {
+            Err(Txt2EpubError::Lint {
+                path,
+                issues: denied,
+            })
+        }
+    }
+
+    fn stats(&self, book: &Book) -> ConvertStats {
+        ConvertStats {
+            part_regex: self.opt.part_regex.to_string(),
+            chapter_regex: describe(&self.opt.chapter_patterns),
+            ..ConvertStats::from(book)
+        }
+    }
+
+    fn finish(&mut self, stats: ConvertStats) -> ConvertResult {
+        ConvertResult {
+            stats,
+            warnings: std::mem::take(&mut self.opt.warnings),
+            removed: std::mem::take(&mut self.opt.removed),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&Book> for ConvertStats {
+    fn from(book: &Book) -> Self {
+        let chapters = book.parts.iter().flat_map(|p| &p.chapters);
+
+        Self {
+            encoding: book.encoding.clone(),
+            parts: if book.has_parts { book.parts.len() } else { 0 },
+            chapters: chapters.clone().count(),
+            words: chapters
+                .clone()
+                .flat_map(|c| &c.lines)
+                .map(|l| count_words(&l.content))
+                .sum(),
+            characters: chapters
+                .flat_map(|c| &c.lines)
+                .map(|l| l.content.chars().count())
+                .sum(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Count a streamed part or chapter, and set `have_section` for writing it.
+fn stream_event(mut event: Event, stats: &mut ConvertStats, opt: &mut ConvertOpt) -> Event {
+    if let Event::Chapter(chapter) = &mut event {
+        if opt.clean.enabled {
+            clean_chapter(chapter, &opt.clean, &mut opt.removed);
+        }
+    }
+
+    match &event {
+        Event::Part(_) => {
+            stats.parts += 1;
+            opt.have_section = true;
+        }
+        Event::Chapter(chapter) => {
+            stats.chapters += 1;
+            stats.words += chapter
+                .content
+                .iter()
+                .map(|l| count_words(&l.content))
+                .sum::<usize>();
+            stats.characters += chapter
+                .content
+                .iter()
+                .map(|l| l.content.chars().count())
+                .sum::<usize>();
+            opt.have_section = chapter.part_no != 0;
+        }
+    }
+
+    event
+}
+
+/// Generate the epub with epub-builder and write it to `out` with [`epub::finish`], so the same
+/// book gives the same bytes.
+fn write_epub<W: Write + Seek>(book: Book, opt: &mut ConvertOpt, out: W) -> Result<W> {
+    let mut epub = EpubFactory::with_default_css()?
+        .epub_version(opt.epub_version)
+        .builder;
+
+    let novel = Novel::from(book);
+    let package = novel.package_metadata();
+    let mut generated = vec![];
+
+    novel
+        .write_to_epub(&mut epub, opt)?
+        .generate(&mut generated)
+        .map_err(Txt2EpubError::epub)?;
+
+    epub::finish(generated, &package, opt.epub_version, out)
+}
+
+/// The size and the sha256 of the file at `path`.
+fn file_digest(path: &Path) -> Result<(u64, String)> {
+    let mut file = File::open(path).map_err(|e| Txt2EpubError::io(path, e))?;
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut file, &mut hasher).map_err(|e| Txt2EpubError::io(path, e))?;
+
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+/// Every CJK character is a word, other words are runs of alphanumeric characters.
+fn count_words(text: &str) -> usize {
+    let mut words = 0;
+    let mut in_word = false;
+
+    for c in text.chars() {
+        if is_cjk(c) {
+            words += 1;
+            in_word = false;
+        } else if c.is_alphanumeric() {
+            if !in_word {
+                words += 1;
+            }
+            in_word = true;
+        } else {
+            in_word = false;
+        }
+    }
+
+    words
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}' // kana
+        | '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{AC00}'..='\u{D7AF}' // hangul
+        | '\u{20000}'..='\u{2FA1F}')
+}
+
+/// Counts and hashes the bytes written through it.
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+    hasher: Sha256,
+}
+
+impl<W> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            count: 0,
+            hasher: Sha256::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count += len as u64;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        error::Error,
+        fs,
+        io::Read,
+        path::Path,
+    };
+
+    use regex::Regex;
+    use roxmltree::{Document, Node, ParsingOptions};
+    use zip::ZipArchive;
+
+    use super::Converter;
+    use crate::{
+        cli::{EpubVersion, HeadingStyle, OutputFormat},
+        heading::{Guard, HeadingGuards},
+        validate::validate_path,
+    };
+
+    const NOVEL: &str = "书名: 测试
+作者: 某人
+简介:
+  - 一个故事
+
+第一卷 开始
+
+卷首语。
+
+第一章 相遇
+
+“你好。”
+***
+正文一。
+
+第二章 别离
+
+正文二。
+
+第二卷 结束
+
+第一章 重逢
+
+正文三。
+";
+
+    /// The pages of an epub in spine order with their content, and the labels of its NCX.
+    #[derive(Debug, PartialEq)]
+    struct ReadingOrder {
+        pages: Vec<(String, Vec<u8>)>,
+        labels: Vec<String>,
+    }
+
+    fn reading_order(path: &Path) -> Result<ReadingOrder, Box<dyn Error>> {
+        let mut zip = ZipArchive::new(fs::File::open(path)?)?;
+        let mut read = |name: &str| -> Result<Vec<u8>, Box<dyn Error>> {
+            let mut content = vec![];
+            zip.by_name(name)?.read_to_end(&mut content)?;
+            Ok(content)
+        };
+
+        let opf = String::from_utf8(read("OEBPS/content.opf")?)?;
+        let opf = Document::parse(&opf)?;
+        let manifest = opf
+            .descendants()
+            .filter(|n| n.has_tag_name("item"))
+            .map(|n| (n.attribute("id").unwrap(), n.attribute("href").unwrap()))
+            .collect::<HashMap<_, _>>();
+
+        let mut pages = vec![];
+
+        for itemref in opf.descendants().filter(|n| n.has_tag_name("itemref")) {
+            let href = manifest[itemref.attribute("idref").unwrap()];
+            pages.push((href.to_string(), read(&format!("OEBPS/{}", href))?));
+        }
+
+        pages.push(("stylesheet.css".into(), read("OEBPS/stylesheet.css")?));
+
+        let ncx = String::from_utf8(read("OEBPS/toc.ncx")?)?;
+        let labels = Document::parse(&ncx)?
+            .descendants()
+            .filter(|n| n.has_tag_name("text") && n.parent().unwrap().has_tag_name("navLabel"))
+            .map(|n| n.text().unwrap_or_default().to_string())
+            .collect();
+
+        Ok(ReadingOrder { pages, labels })
+    }
+
+    /// What the package of an epub is made of: the entries of the archive, the manifest by href
+    /// with the media type and properties, the spine as hrefs, and the NCX and nav entries with
+    /// their depth, label and target.
+    #[derive(Debug, PartialEq)]
+    struct Package {
+        entries: Vec<String>,
+        manifest: BTreeMap<String, (String, Option<String>)>,
+        spine: Vec<String>,
+        ncx: Vec<(usize, String, String)>,
+        nav: Vec<(usize, String, String)>,
+    }
+
+    fn package(path: &Path) -> Result<Package, Box<dyn Error>> {
+        let mut zip = ZipArchive::new(fs::File::open(path)?)?;
+        let mut entries = zip.file_names().map(String::from).collect::<Vec<_>>();
+        entries.sort();
+
+        let mut read = |name: &str| -> Result<String, Box<dyn Error>> {
+            let mut content = String::new();
+            zip.by_name(name)?.read_to_string(&mut content)?;
+            Ok(content)
+        };
+
+        let opf = read("OEBPS/content.opf")?;
+        let opf = Document::parse(&opf)?;
+        let items = opf
+            .descendants()
+            .filter(|n| n.has_tag_name("item"))
+            .collect::<Vec<_>>();
+        let href = |id: &str| {
+            items
+                .iter()
+                .find(|n| n.attribute("id") == Some(id))
+                .and_then(|n| n.attribute("href"))
+                .unwrap()
+                .to_string()
+        };
+
+        let manifest = items
+            .iter()
+            .map(|n| {
+                let media_type = n.attribute("media-type").unwrap().to_string();
+                let properties = n.attribute("properties").map(String::from);
+                (
+                    n.attribute("href").unwrap().to_string(),
+                    (media_type, properties),
+                )
+            })
+            .collect();
+        let spine = opf
+            .descendants()
+            .filter(|n| n.has_tag_name("itemref"))
+            .map(|n| href(n.attribute("idref").unwrap()))
+            .collect();
+        let toc = opf
+            .descendants()
+            .find(|n| n.has_tag_name("spine"))
+            .and_then(|n| n.attribute("toc"))
+            .unwrap();
+        assert_eq!(href(toc), "toc.ncx");
+
+        let depth =
+            |node: Node, tag: &str| node.ancestors().filter(|n| n.has_tag_name(tag)).count();
+
+        let ncx = read("OEBPS/toc.ncx")?;
+        let ncx = Document::parse(&ncx)?
+            .descendants()
+            .filter(|n| n.has_tag_name("navPoint"))
+            .map(|n| {
+                let label = n
+                    .descendants()
+                    .find(|n| n.has_tag_name("text"))
+                    .and_then(|n| n.text())
+                    .unwrap_or_default();
+                let src = n
+                    .children()
+                    .find(|n| n.has_tag_name("content"))
+                    .and_then(|n| n.attribute("src"))
+                    .unwrap();
+                (depth(n, "navPoint"), label.to_string(), src.to_string())
+            })
+            .collect();
+
+        let options = ParsingOptions {
+            allow_dtd: true,
+            ..ParsingOptions::default()
+        };
+        let nav = match read("OEBPS/nav.xhtml") {
+            Ok(nav) => Document::parse_with_options(&nav, options)?
+                .descendants()
+                .filter(|n| n.has_tag_name("a"))
+                .map(|n| {
+                    let label = n.text().unwrap_or_default().to_string();
+                    let href = n.attribute("href").unwrap().to_string();
+                    (depth(n, "ol"), label, href)
+                })
+                .collect(),
+            Err(_) => vec![],
+        };
+
+        Ok(Package {
+            entries,
+            manifest,
+            spine,
+            ncx,
+            nav,
+        })
+    }
+
+    #[test]
+    fn streamed_output_is_the_same() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("txt2epub-stream-{}", std::process::id()));
+        let input = dir.join("novel.txt");
+
+        fs::create_dir_all(&dir)?;
+        fs::write(&input, NOVEL)?;
+
+        for format in [OutputFormat::Txt, OutputFormat::Epub, OutputFormat::Kepub] {
+            let (whole, streamed) = (dir.join("whole"), dir.join("streamed"));
+
+            let mut converter = Converter::new().format(format);
+            let book = converter.parse_path(&input)?;
+            let expected = converter.write_to_path(book, &whole)?;
+
+            let mut converter = Converter::new().format(format).stream(true);
+            let result = converter.stream_to_path(&input, &streamed)?;
+
+            assert_eq!(result.stats.parts, expected.stats.parts);
+            assert_eq!(result.stats.chapters, expected.stats.chapters);
+            assert_eq!(result.stats.characters, expected.stats.characters);
+            assert_eq!(result.stats.output_size, fs::metadata(&streamed)?.len());
+
+            if format == OutputFormat::Txt {
+                assert_eq!(fs::read_to_string(&streamed)?, fs::read_to_string(&whole)?);
+                assert_eq!(result.checksum, expected.checksum);
+                continue;
+            }
+
+            let order = reading_order(&streamed)?;
+
+            assert_eq!(order, reading_order(&whole)?);
+            assert_eq!(order.pages.len(), 7);
+            assert_eq!(order.labels[1], "第一卷 开始");
+            let problems = validate_path(&streamed)?;
+            assert!(problems.is_empty(), "{:?}", problems);
+        }
+
+        fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn streamed_epub_has_the_same_package() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("txt2epub-package-{}", std::process::id()));
+        let input = dir.join("novel.txt");
+
+        fs::create_dir_all(&dir)?;
+        fs::write(&input, NOVEL)?;
+
+        for version in [EpubVersion::V2, EpubVersion::V3] {
+            let (whole, streamed) = (dir.join("whole.epub"), dir.join("streamed.epub"));
+
+            let mut converter = Converter::new().epub_version(version);
+            let book = converter.parse_path(&input)?;
+            converter.write_to_path(book, &whole)?;
+
+            let mut converter = Converter::new().epub_version(version).stream(true);
+            converter.stream_to_path(&input, &streamed)?;
+
+            let expected = package(&whole)?;
+
+            assert_eq!(package(&streamed)?, expected);
+            assert_eq!(expected.spine.len(), 6);
+            assert_eq!(expected.ncx[2].0, 2);
+            assert_eq!(expected.nav.is_empty(), version == EpubVersion::V2);
+        }
+
+        fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn escaped_text() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("txt2epub-escape-{}", std::process::id()));
+        let input = dir.join("novel.txt");
+        let text =
+            "书名: \"A & B <c>\"\n简介:\n  - \"A & B <c>\"\n\n第一章 A & B <c>\n\nA & B <c>\n";
+
+        fs::create_dir_all(&dir)?;
+        fs::write(&input, text)?;
+
+        for format in [OutputFormat::Epub, OutputFormat::Kepub] {
+            let (whole, streamed) = (dir.join("whole"), dir.join("streamed"));
+
+            let mut converter = Converter::new().format(format);
+            let book = converter.parse_path(&input)?;
+            converter.write_to_path(book, &whole)?;
+
+            let mut converter = Converter::new().format(format).stream(true);
+            converter.stream_to_path(&input, &streamed)?;
+
+            for path in [whole, streamed] {
+                let problems = validate_path(&path)?;
+                assert!(problems.is_empty(), "{:?}", problems);
+
+                let order = reading_order(&path)?;
+                let chapter = String::from_utf8(order.pages[1].1.clone())?;
+                assert!(chapter.contains("A &amp; B &lt;c&gt;</"));
+                assert!(!chapter.contains("A & B"));
+            }
+        }
+
+        fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn epub2_has_ncx_and_no_nav() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("txt2epub-epub2-{}", std::process::id()));
+        let input = dir.join("novel.txt");
+
+        fs::create_dir_all(&dir)?;
+        fs::write(&input, NOVEL)?;
+
+        let (whole, streamed) = (dir.join("whole.epub"), dir.join("streamed.epub"));
+
+        let mut converter = Converter::new().epub_version(EpubVersion::V2);
+        let book = converter.parse_path(&input)?;
+        converter.write_to_path(book, &whole)?;
+
+        let mut converter = Converter::new().epub_version(EpubVersion::V2).stream(true);
+        converter.stream_to_path(&input, &streamed)?;
+
+        for path in [whole, streamed] {
+            let mut zip = ZipArchive::new(fs::File::open(&path)?)?;
+            assert!(zip.by_name("OEBPS/toc.ncx").is_ok());
+            assert!(zip.by_name("OEBPS/nav.xhtml").is_err());
+
+            let mut opf = String::new();
+            zip.by_name("OEBPS/content.opf")?.read_to_string(&mut opf)?;
+            let opf = Document::parse(&opf)?;
+            let package = opf.root_element();
+            assert_eq!(package.attribute("version"), Some("2.0"));
+            assert!(opf
+                .descendants()
+                .filter(|n| n.has_tag_name("item"))
+                .all(|n| n.attribute("properties").is_none()));
+            assert!(!reading_order(&path)?.labels.is_empty());
+        }
+
+        fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn package_metadata() -> Result<(), Box<dyn Error>> {
+        let text = "书名: 测试
+作者: 某人
+系列: 某系列
+系列序号: 2
+标签: [奇幻, 冒险]
+译者: 张三
+出版日期: 2020-05
+ISBN: 978-7-5442-9116-9
+
+第一章 相遇
+
+正文。
+";
+        let dir = std::env::temp_dir().join(format!("txt2epub-opf-{}", std::process::id()));
+        let input = dir.join("novel.txt");
+
+        fs::create_dir_all(&dir)?;
+        fs::write(&input, text)?;
+
+        for version in [EpubVersion::V2, EpubVersion::V3] {
+            let path = dir.join("novel.epub");
+            let mut converter = Converter::new().epub_version(version);
+            let book = converter.parse_path(&input)?;
+            converter.write_to_path(book, &path)?;
+
+            let mut opf = String::new();
+            ZipArchive::new(fs::File::open(&path)?)?
+                .by_name("OEBPS/content.opf")?
+                .read_to_string(&mut opf)?;
+            let doc = Document::parse(&opf)?;
+            let texts = |tag: &str| {
+                doc.descendants()
+                    .filter(|n| n.tag_name().name() == tag)
+                    .map(|n| n.text().unwrap_or_default())
+                    .collect::<Vec<_>>()
+            };
+            let meta = |name: &str| {
+                doc.descendants()
+                    .find(|n| n.attribute("name") == Some(name))
+                    .and_then(|n| n.attribute("content"))
+            };
+
+            assert_eq!(texts("subject"), ["奇幻", "冒险"]);
+            assert_eq!(texts("contributor"), ["张三"]);
+            assert_eq!(texts("date"), ["2020-05"]);
+            assert_eq!(meta("calibre:series"), Some("某系列"));
+            assert_eq!(meta("calibre:series_index"), Some("2"));
+
+            if version == EpubVersion::V3 {
+                assert!(texts("identifier").contains(&"urn:isbn:9787544291169"));
+                assert!(opf.contains(r#"property="role" scheme="marc:relators">trl</meta>"#));
+                assert!(
+                    opf.contains(r#"property="belongs-to-collection" id="series">某系列</meta>"#)
+                );
+                assert!(opf.contains(r#"property="group-position">2</meta>"#));
+            } else {
+                assert!(opf.contains(r#"opf:scheme="ISBN">9787544291169</dc:identifier>"#));
+                assert!(opf.contains(r#"opf:role="trl">张三</dc:contributor>"#));
+            }
+
+            let problems = validate_path(&path)?;
+            assert!(problems.is_empty(), "{:?}", problems);
+        }
+
+        fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn reproducible_output() -> Result<(), Box<dyn Error>> {
+        let convert = || -> Result<Vec<u8>, Box<dyn Error>> {
+            let mut converter = Converter::new();
+            let book = converter.parse_bytes(NOVEL.as_bytes())?;
+            let mut out = vec![];
+            converter.write(book, &mut out)?;
+            Ok(out)
+        };
+        let opf = |epub: &[u8]| -> Result<String, Box<dyn Error>> {
+            let mut opf = String::new();
+            ZipArchive::new(std::io::Cursor::new(epub))?
+                .by_name("OEBPS/content.opf")?
+                .read_to_string(&mut opf)?;
+            Ok(opf)
+        };
+
+        let epub = convert()?;
+        assert_eq!(convert()?, epub);
+        assert!(opf(&epub)?.contains("<dc:date>1980-01-01T00:00:00Z</dc:date>"));
+
+        std::env::set_var("SOURCE_DATE_EPOCH", "1700000000");
+        let (first, second) = (convert(), convert());
+        std::env::remove_var("SOURCE_DATE_EPOCH");
+
+        let epub = first?;
+        assert_eq!(second?, epub);
+        assert!(opf(&epub)?.contains("<dc:date>2023-11-14T22:13:20Z</dc:date>"));
+
+        let mut zip = ZipArchive::new(std::io::Cursor::new(&epub))?;
+        for i in 0..zip.len() {
+            let time = zip.by_index(i)?.last_modified();
+            assert_eq!((time.year(), time.month(), time.day()), (2023, 11, 14));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn raw_heading_style() -> Result<(), Box<dyn Error>> {
+        let text = "第1卷 开始\n\n第108章 决战（上）\n\n正文。\n";
+        let dir = std::env::temp_dir().join(format!("txt2epub-raw-{}", std::process::id()));
+        let input = dir.join("novel.txt");
+
+        fs::create_dir_all(&dir)?;
+        fs::write(&input, text)?;
+
+        let path = dir.join("novel.epub");
+        let mut converter = Converter::new().heading_style(HeadingStyle::Raw);
+        let book = converter.parse_path(&input)?;
+        converter.write_to_path(book, &path)?;
+
+        let order = reading_order(&path)?;
+        assert_eq!(order.labels[1..], ["第1卷 开始", "第108章 决战（上）"]);
+
+        let chapter = String::from_utf8(order.pages[2].1.clone())?;
+        assert!(chapter.contains("<h2 class=\"title\">第108章 决战（上）</h2>"));
+        assert!(!chapter.contains("<h3 class=\"no\">"));
+
+        let mut converter = Converter::new();
+        let book = converter.parse_path(&input)?;
+        converter.write_to_path(book, &path)?;
+        assert_eq!(reading_order(&path)?.labels[2], "第一章 决战（上）");
+
+        let path = dir.join("novel.clean.txt");
+        let mut converter = Converter::new()
+            .format(OutputFormat::Txt)
+            .heading_style(HeadingStyle::Raw);
+        let book = converter.parse_path(&input)?;
+        converter.write_to_path(book, &path)?;
+
+        let txt = fs::read_to_string(&path)?;
+        assert!(txt.contains("第1卷 开始\n"));
+        assert!(txt.contains("第108章 决战（上）\n"));
+
+        fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn guards_before_the_first_heading() -> Result<(), Box<dyn Error>> {
+        let text = "第三章的内容他已经忘了，\n\n第一章 相遇\n\n正文。\n";
+        let book = Converter::new()
+            .chapter_regex(Regex::new("^第.+章(.*)$")?)
+            .guards(HeadingGuards {
+                guards: vec![Guard::Punctuation],
+                ..Default::default()
+            })
+            .parse_bytes(text.as_bytes())?;
+
+        assert_eq!(book.metadata.preface, ["第三章的内容他已经忘了，"]);
+        assert!(!book.has_parts);
+
+        let part = &book.parts[0];
+        let titles = part
+            .chapters
+            .iter()
+            .map(|c| c.raw_title.trim())
+            .collect::<Vec<_>>();
+        assert!(part.preface.is_empty());
+        assert_eq!(titles, ["第一章 相遇"]);
+
+        Ok(())
+    }
+}
@@ -1,6 +1,45 @@
-use epub_builder::{EpubBuilder, ZipLibrary};
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read, Seek, Write},
+};
 
-use crate::{error::AnyError, NOVEL_CSS};
+use epub_builder::{EpubBuilder, EpubContent, ZipLibrary};
+use lazy_static::lazy_static;
+use log::warn;
+use regex::{Captures, Regex};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tera::Context;
+use zip::{write::FileOptions, CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+use crate::{
+    cli::EpubVersion,
+    error::{Result, Txt2EpubError},
+    NOVEL_CSS, TEMPLATE_ENGINE,
+};
+
+const CONTAINER: &str = "META-INF/container.xml";
+/// The directory of the package document and the pages, as epub-builder lays them out.
+const OEBPS: &str = "OEBPS";
+const CONTAINER_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+const OPF_NS: &str = "http://www.idpf.org/2007/opf";
+/// 1980-01-01, the earliest time a zip entry can have, used without `SOURCE_DATE_EPOCH`.
+const ZIP_EPOCH: u64 = 315_532_800;
+
+lazy_static! {
+    static ref ROOTFILE: Regex = Regex::new(r#"full-path="([^"]+)""#).unwrap();
+    static ref IDENTIFIER: Regex =
+        Regex::new(r"(<dc:identifier\b[^>]*>)([^<]*)(</dc:identifier>)").unwrap();
+    static ref DATE: Regex = Regex::new(r"(<dc:date>)([^<]*)(</dc:date>)").unwrap();
+    static ref MODIFIED: Regex =
+        Regex::new(r#"(<meta property="dcterms:modified">)([^<]*)(</meta>)"#).unwrap();
+}
 
 pub struct EpubFactory {
     pub builder: EpubBuilder<ZipLibrary>,
@@ -13,15 +52,579 @@ impl From<EpubFactory> for EpubBuilder<ZipLibrary> {
 }
 
 impl EpubFactory {
-    pub fn with_default_css() -> Result<Self, AnyError> {
-        let mut epub = EpubBuilder::new(ZipLibrary::new()?)?;
-        epub.stylesheet(NOVEL_CSS.as_bytes())?;
+    pub fn with_default_css() -> Result<Self> {
+        let mut epub = EpubBuilder::new(ZipLibrary::new().map_err(Txt2EpubError::epub)?)
+            .map_err(Txt2EpubError::epub)?;
+        epub.stylesheet(NOVEL_CSS.as_bytes())
+            .map_err(Txt2EpubError::epub)?;
         epub.epub_version(epub_builder::EpubVersion::V30);
         Ok(Self { builder: epub })
     }
 
-    pub fn default_css(&mut self) -> Result<&mut Self, AnyError> {
-        self.builder.stylesheet(NOVEL_CSS.as_bytes())?;
+    /// Both versions carry an NCX toc, so readers that only understand EPUB 2 still get one.
+    pub fn epub_version(mut self, version: EpubVersion) -> Self {
+        self.builder.epub_version(version.into());
+        self
+    }
+
+    pub fn default_css(&mut self) -> Result<&mut Self> {
+        self.builder
+            .stylesheet(NOVEL_CSS.as_bytes())
+            .map_err(Txt2EpubError::epub)?;
         Ok(self)
     }
 }
+
+/// Where the book is written to, the epub-builder of a whole book or an [`EpubStream`].
+pub(crate) trait EpubSink {
+    /// `title`, `author`, `lang`, `toc_name` or `description`.
+    fn metadata(&mut self, key: &str, value: String) -> Result<()>;
+
+    /// A jpeg cover, `path` is relative to the package document.
+    fn add_cover_image(&mut self, path: &str, image: &[u8]) -> Result<()>;
+
+    /// A page in reading order, `level` is its depth in the TOC from 1.
+    fn add_page(&mut self, href: &str, title: &str, level: i32, html: &str) -> Result<()>;
+}
+
+impl EpubSink for EpubBuilder<ZipLibrary> {
+    fn metadata(&mut self, key: &str, value: String) -> Result<()> {
+        EpubBuilder::metadata(self, key, value).map_err(Txt2EpubError::epub)?;
+        Ok(())
+    }
+
+    fn add_cover_image(&mut self, path: &str, image: &[u8]) -> Result<()> {
+        EpubBuilder::add_cover_image(self, path, image, "image/jpeg")
+            .map_err(Txt2EpubError::epub)?;
+        Ok(())
+    }
+
+    fn add_page(&mut self, href: &str, title: &str, level: i32, html: &str) -> Result<()> {
+        self.add_content(
+            EpubContent::new(href, html.as_bytes())
+                .title(title)
+                .level(level),
+        )
+        .map_err(Txt2EpubError::epub)?;
+        Ok(())
+    }
+}
+
+/// A page of an [`EpubStream`], what the package document and the TOC need of it.
+#[derive(Serialize, Debug)]
+struct TocEntry {
+    href: String,
+    title: String,
+    /// position in the reading order, from 1.
+    order: usize,
+    #[serde(skip)]
+    level: i32,
+    children: Vec<TocEntry>,
+}
+
+/// Writes the epub while the txt is read, for [`crate::converter::Converter::stream_to_path`].
+///
+/// A page goes into the archive as soon as it is added and only its TOC entry is kept. The
+/// package document, the NCX and the nav document are written last, by [`EpubStream::finish`].
+/// The entries are laid out as epub-builder does and carry the same time as in [`finish`], but
+/// they are in the order they are written instead of sorted.
+pub(crate) struct EpubStream<W: Write + Seek> {
+    zip: ZipWriter<W>,
+    options: FileOptions,
+    version: EpubVersion,
+    time: u64,
+    metadata: HashMap<String, String>,
+    cover: Option<String>,
+    pages: Vec<TocEntry>,
+}
+
+impl<W: Write + Seek> EpubStream<W> {
+    /// Start the archive with the `mimetype`, the container and the stylesheet.
+    pub(crate) fn new(out: W, version: EpubVersion) -> Result<Self> {
+        let time = source_date_epoch().unwrap_or(ZIP_EPOCH);
+        let mut epub = Self {
+            zip: ZipWriter::new(out),
+            options: FileOptions::default()
+                .last_modified_time(zip_time(time))
+                .unix_permissions(0o644),
+            version,
+            time,
+            metadata: HashMap::new(),
+            cover: None,
+            pages: vec![],
+        };
+
+        epub.write("mimetype", b"application/epub+zip")?;
+        epub.write(CONTAINER, CONTAINER_XML.as_bytes())?;
+        epub.write(&format!("{}/stylesheet.css", OEBPS), NOVEL_CSS.as_bytes())?;
+
+        Ok(epub)
+    }
+
+    /// Write the package document completed by `metadata`, the NCX and the nav document, then
+    /// the central directory of the archive.
+    pub(crate) fn finish(mut self, metadata: &PackageMetadata) -> Result<W> {
+        let pages = std::mem::take(&mut self.pages);
+        let depth = pages.iter().map(|p| p.level).max().unwrap_or(1);
+        let mut context = Context::new();
+
+        for key in ["title", "author", "lang", "toc_name", "description"] {
+            context.insert(key, self.metadata.get(key).map_or("", String::as_str));
+        }
+
+        context.insert("uuid", &metadata.uuid());
+        context.insert("epub_version", &(self.version as u8));
+        context.insert("cover", &self.cover);
+        context.insert("pages", &pages);
+        context.insert("depth", &depth);
+        context.insert("toc", &nest(pages));
+
+        let opf = metadata.apply(&render("opf", &context)?, self.version, self.time);
+        self.write(&format!("{}/content.opf", OEBPS), opf.as_bytes())?;
+        self.write(
+            &format!("{}/toc.ncx", OEBPS),
+            render("ncx", &context)?.as_bytes(),
+        )?;
+
+        if self.version == EpubVersion::V3 {
+            self.write(
+                &format!("{}/nav.xhtml", OEBPS),
+                render("nav", &context)?.as_bytes(),
+            )?;
+        }
+
+        self.zip.finish().map_err(Txt2EpubError::epub)
+    }
+
+    fn write(&mut self, name: &str, content: &[u8]) -> Result<()> {
+        let method = match name {
+            "mimetype" => CompressionMethod::Stored,
+            _ => CompressionMethod::Deflated,
+        };
+
+        self.zip
+            .start_file(name, self.options.compression_method(method))
+            .map_err(Txt2EpubError::epub)?;
+        self.zip.write_all(content)?;
+
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> EpubSink for EpubStream<W> {
+    fn metadata(&mut self, key: &str, value: String) -> Result<()> {
+        self.metadata.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn add_cover_image(&mut self, path: &str, image: &[u8]) -> Result<()> {
+        self.write(&format!("{}/{}", OEBPS, path), image)?;
+        self.cover = Some(path.to_string());
+        Ok(())
+    }
+
+    fn add_page(&mut self, href: &str, title: &str, level: i32, html: &str) -> Result<()> {
+        self.write(&format!("{}/{}", OEBPS, href), html.as_bytes())?;
+        self.pages.push(TocEntry {
+            href: href.to_string(),
+            title: title.to_string(),
+            order: self.pages.len() + 1,
+            level,
+            children: vec![],
+        });
+        Ok(())
+    }
+}
+
+/// Put the pages of level 2 and below under the page before them.
+fn nest(pages: Vec<TocEntry>) -> Vec<TocEntry> {
+    let mut toc: Vec<TocEntry> = vec![];
+
+    for page in pages {
+        match toc.last_mut() {
+            Some(parent) if page.level > parent.level => parent.children.push(page),
+            _ => toc.push(page),
+        }
+    }
+
+    toc
+}
+
+fn render(template: &str, context: &Context) -> Result<String> {
+    TEMPLATE_ENGINE
+        .render(template, context)
+        .map_err(|e| Txt2EpubError::template(template, e))
+}
+
+impl From<EpubVersion> for epub_builder::EpubVersion {
+    fn from(value: EpubVersion) -> Self {
+        match value {
+            EpubVersion::V2 => epub_builder::EpubVersion::V20,
+            EpubVersion::V3 => epub_builder::EpubVersion::V30,
+        }
+    }
+}
+
+/// The metadata epub-builder has no field for, added to the package document after generating.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PackageMetadata {
+    /// the identifier is derived from the title and author if `uuid` is not set.
+    pub title: String,
+    pub author: String,
+    pub series: Option<String>,
+    pub series_index: Option<u32>,
+    pub tags: Vec<String>,
+    pub publisher: Option<String>,
+    pub published: Option<String>,
+    pub modified: Option<String>,
+    pub isbn: Option<String>,
+    pub uuid: Option<String>,
+    pub translators: Vec<String>,
+    pub illustrators: Vec<String>,
+    pub rights: Option<String>,
+    pub source: Option<String>,
+}
+
+impl PackageMetadata {
+    /// The pinned uuid, or one derived from the title and author so that converting the book
+    /// again keeps its identity, and readers their progress.
+    fn uuid(&self) -> String {
+        if let Some(uuid) = &self.uuid {
+            return uuid.clone();
+        }
+
+        let mut hasher = Sha256::new();
+
+        for field in [&self.title, &self.author] {
+            hasher.update(field.len().to_le_bytes());
+            hasher.update(field);
+        }
+
+        let mut bytes = hasher.finalize();
+        // a version 8 uuid, the layout of RFC 9562 with our own hash.
+        bytes[6] = (bytes[6] & 0x0f) | 0x80;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+        let hex = bytes[..16]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+
+    /// Add the metadata to the package document, and replace the random identifier and the
+    /// build time epub-builder writes by stable ones.
+    fn apply(&self, opf: &str, version: EpubVersion, time: u64) -> String {
+        let v3 = version == EpubVersion::V3;
+        let mut opf = opf.to_string();
+        let mut elements = vec![];
+
+        replace_text(&mut opf, &IDENTIFIER, &format!("urn:uuid:{}", self.uuid()));
+
+        match &self.published {
+            Some(published) => {
+                if !replace_text(&mut opf, &DATE, published) {
+                    elements.push(format!("<dc:date>{}</dc:date>", escape(published)));
+                }
+            }
+            None => {
+                replace_text(&mut opf, &DATE, &format_time(time));
+            }
+        }
+
+        let modified = match &self.modified {
+            Some(modified) if modified.len() == 10 => Some(format!("{}T00:00:00Z", modified)),
+            modified => modified.clone(),
+        };
+
+        let stamp = modified.clone().unwrap_or_else(|| format_time(time));
+
+        if !replace_text(&mut opf, &MODIFIED, &stamp) && v3 {
+            elements.push(format!(
+                r#"<meta property="dcterms:modified">{}</meta>"#,
+                escape(&stamp)
+            ));
+        }
+
+        if let Some(modified) = modified.filter(|_| !v3) {
+            elements.push(format!(
+                r#"<dc:date xmlns:opf="{}" opf:event="modification">{}</dc:date>"#,
+                OPF_NS,
+                escape(&modified)
+            ));
+        }
+
+        if let Some(isbn) = &self.isbn {
+            elements.push(if v3 {
+                format!(
+                    r#"<dc:identifier id="isbn">urn:isbn:{}</dc:identifier>"#,
+                    isbn
+                )
+            } else {
+                format!(
+                    r#"<dc:identifier xmlns:opf="{}" opf:scheme="ISBN">{}</dc:identifier>"#,
+                    OPF_NS, isbn
+                )
+            });
+        }
+
+        for (tag, value) in [
+            ("publisher", &self.publisher),
+            ("rights", &self.rights),
+            ("source", &self.source),
+        ] {
+            if let Some(value) = value {
+                elements.push(format!("<dc:{0}>{1}</dc:{0}>", tag, escape(value)));
+            }
+        }
+
+        for tag in &self.tags {
+            elements.push(format!("<dc:subject>{}</dc:subject>", escape(tag)));
+        }
+
+        // MARC relator codes.
+        let contributors = self
+            .translators
+            .iter()
+            .map(|name| (name, "trl"))
+            .chain(self.illustrators.iter().map(|name| (name, "ill")));
+
+        for (i, (name, role)) in contributors.enumerate() {
+            if v3 {
+                elements.push(format!(
+                    r#"<dc:contributor id="contributor-{}">{}</dc:contributor>"#,
+                    i + 1,
+                    escape(name)
+                ));
+                elements.push(format!(
+                    r##"<meta refines="#contributor-{}" property="role" scheme="marc:relators">{}</meta>"##,
+                    i + 1,
+                    role
+                ));
+            } else {
+                elements.push(format!(
+                    r#"<dc:contributor xmlns:opf="{}" opf:role="{}">{}</dc:contributor>"#,
+                    OPF_NS,
+                    role,
+                    escape(name)
+                ));
+            }
+        }
+
+        if let Some(series) = &self.series {
+            if v3 {
+                elements.push(format!(
+                    r#"<meta property="belongs-to-collection" id="series">{}</meta>"#,
+                    escape(series)
+                ));
+                elements.push(
+                    r##"<meta refines="#series" property="collection-type">series</meta>"##.into(),
+                );
+
+                if let Some(index) = self.series_index {
+                    elements.push(format!(
+                        r##"<meta refines="#series" property="group-position">{}</meta>"##,
+                        index
+                    ));
+                }
+            }
+
+            // calibre and most readers only know its own series meta.
+            elements.push(format!(
+                r#"<meta name="calibre:series" content="{}"/>"#,
+                escape(series)
+            ));
+
+            if let Some(index) = self.series_index {
+                elements.push(format!(
+                    r#"<meta name="calibre:series_index" content="{}"/>"#,
+                    index
+                ));
+            }
+        }
+
+        if let Some(end) = opf.find("</metadata>") {
+            let elements = elements
+                .iter()
+                .map(|e| format!("{}\n  ", e))
+                .collect::<String>();
+
+            opf.insert_str(end, &elements);
+        }
+
+        opf
+    }
+}
+
+/// Write the epub generated by epub-builder to `out`, with the package document completed by
+/// `metadata`.
+///
+/// The archive is rebuilt so that the same input gives the same bytes: the entries are sorted
+/// with `mimetype` first, and carry the time of `SOURCE_DATE_EPOCH`, or 1980-01-01. Every entry
+/// but the package document and the NCX is copied straight from `epub` into `out`.
+pub(crate) fn finish<W: Write + Seek>(
+    epub: Vec<u8>,
+    metadata: &PackageMetadata,
+    version: EpubVersion,
+    out: W,
+) -> Result<W> {
+    let time = source_date_epoch().unwrap_or(ZIP_EPOCH);
+    let mut zip = ZipArchive::new(Cursor::new(epub)).map_err(Txt2EpubError::epub)?;
+
+    let container = read_to_string(&mut zip, CONTAINER)?;
+    let opf_path = ROOTFILE
+        .captures(&container)
+        .map(|c| c[1].to_string())
+        .ok_or_else(|| Txt2EpubError::epub("no package document in the container"))?;
+    let opf = read_to_string(&mut zip, &opf_path)?;
+
+    // the random uuid of epub-builder, also written in the NCX.
+    let random_uuid = IDENTIFIER
+        .captures(&opf)
+        .map(|c| c[2].trim_start_matches("urn:uuid:").to_string())
+        .filter(|uuid| !uuid.is_empty());
+    let opf = metadata.apply(&opf, version, time);
+
+    let mut names = zip.file_names().map(String::from).collect::<Vec<_>>();
+    names.sort_by(|a, b| (a != "mimetype", a).cmp(&(b != "mimetype", b)));
+
+    let mut writer = ZipWriter::new(out);
+    let options = FileOptions::default()
+        .last_modified_time(zip_time(time))
+        .unix_permissions(0o644);
+
+    for name in names {
+        if name.ends_with('/') {
+            writer
+                .add_directory(name.as_str(), options)
+                .map_err(Txt2EpubError::epub)?;
+            continue;
+        }
+
+        let method = match name.as_str() {
+            "mimetype" => CompressionMethod::Stored,
+            _ => CompressionMethod::Deflated,
+        };
+
+        writer
+            .start_file(name.as_str(), options.compression_method(method))
+            .map_err(Txt2EpubError::epub)?;
+
+        if name == opf_path {
+            writer.write_all(opf.as_bytes())?;
+        } else if let Some(uuid) = random_uuid.as_ref().filter(|_| name.ends_with(".ncx")) {
+            let ncx = read_to_string(&mut zip, &name)?;
+            writer.write_all(ncx.replace(uuid.as_str(), &metadata.uuid()).as_bytes())?;
+        } else {
+            let mut entry = zip.by_name(&name).map_err(Txt2EpubError::epub)?;
+            std::io::copy(&mut entry, &mut writer)?;
+        }
+    }
+
+    writer.finish().map_err(Txt2EpubError::epub)
+}
+
+/// Seconds since the epoch from `SOURCE_DATE_EPOCH`, see <https://reproducible-builds.org/specs/source-date-epoch/>.
+fn source_date_epoch() -> Option<u64> {
+    let value = std::env::var("SOURCE_DATE_EPOCH").ok()?;
+
+    value
+        .trim()
+        .parse()
+        .inspect_err(|_| warn!("ignoring invalid SOURCE_DATE_EPOCH `{}`", value))
+        .ok()
+}
+
+/// `YYYY-MM-DDThh:mm:ssZ`.
+fn format_time(time: u64) -> String {
+    let (year, month, day, hour, minute, second) = civil_time(time);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// Zip entries can only hold 1980 to 2107.
+fn zip_time(time: u64) -> DateTime {
+    let (year, month, day, hour, minute, second) = civil_time(time);
+
+    match year {
+        ..=1979 => DateTime::default(),
+        2108.. => DateTime::from_date_and_time(2107, 12, 31, 23, 59, 58).unwrap(),
+        year => DateTime::from_date_and_time(
+            year as u16,
+            month as u8,
+            day as u8,
+            hour as u8,
+            minute as u8,
+            second as u8,
+        )
+        .unwrap_or_default(),
+    }
+}
+
+/// The UTC date and time of a unix timestamp, after Howard Hinnant's `civil_from_days`.
+fn civil_time(time: u64) -> (u64, u64, u64, u64, u64, u64) {
+    let (days, seconds) = (time / 86400, time % 86400);
+
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    (
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+    )
+}
+
+fn read_to_string(zip: &mut ZipArchive<Cursor<Vec<u8>>>, name: &str) -> Result<String> {
+    let mut content = String::new();
+
+    zip.by_name(name)
+        .map_err(Txt2EpubError::epub)?
+        .read_to_string(&mut content)?;
+
+    Ok(content)
+}
+
+/// Replace the text of the first element matched by `regex`, false if there is none.
+fn replace_text(opf: &mut String, regex: &Regex, text: &str) -> bool {
+    if !regex.is_match(opf) {
+        return false;
+    }
+
+    *opf = regex
+        .replacen(opf, 1, |c: &Captures| {
+            format!("{}{}{}", &c[1], escape(text), &c[3])
+        })
+        .into_owned();
+
+    true
+}
+
+/// Escape the text for XML content and attribute values.
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
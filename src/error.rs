@@ -1 +1,120 @@
-pub type AnyError = Box<dyn std::error::Error>;
+use std::{fmt::Display, io, path::PathBuf};
+
+use thiserror::Error;
+
+use crate::{lint::Issue, validate::Problem};
+
+pub type Result<T, E = Txt2EpubError> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum Txt2EpubError {
+    #[error("{}{source}", path_prefix(.path))]
+    Io {
+        path: Option<PathBuf>,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("{}: the text is not valid {encoding}, {replaced} characters are malformed", .path.display())]
+    Encoding {
+        path: PathBuf,
+        encoding: String,
+        replaced: usize,
+    },
+
+    #[error("{}:{line}:{column}: invalid metadata header, {message}", .path.display())]
+    Metadata {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+
+    #[error("failed to render template `{name}`, {source}")]
+    Template {
+        name: String,
+        #[source]
+        source: tera::Error,
+    },
+
+    #[error("{}:{line}: {message}", .path.display())]
+    Structure {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+
+    #[error("{}: {} lint errors, the first one at {}", .path.display(), .issues.len(), .issues[0])]
+    Lint { path: PathBuf, issues: Vec<Issue> },
+
+    #[error("{}: invalid epub, {} problems, the first one in {}", .path.display(), .problems.len(), .problems[0])]
+    Validate {
+        path: PathBuf,
+        problems: Vec<Problem>,
+    },
+
+    #[error("failed to fetch cover image `{url}`, {message}")]
+    Cover { url: String, message: String },
+
+    #[error("failed to build epub, {0}")]
+    Epub(String),
+}
+
+impl Txt2EpubError {
+    pub(crate) fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        Self::Io {
+            path: Some(path.into()),
+            source,
+        }
+    }
+
+    pub(crate) fn template(name: &str, source: tera::Error) -> Self {
+        Self::Template {
+            name: name.to_string(),
+            source,
+        }
+    }
+
+    pub(crate) fn epub(error: impl Display) -> Self {
+        Self::Epub(error.to_string())
+    }
+
+    /// The file the error comes from, if known.
+    pub fn path(&self) -> Option<&PathBuf> {
+        match self {
+            Self::Io { path, .. } => path.as_ref(),
+            Self::Encoding { path, .. }
+            | Self::Metadata { path, .. }
+            | Self::Structure { path, .. }
+            | Self::Lint { path, .. }
+            | Self::Validate { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The line number in the source file the error comes from, if known.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Self::Metadata { line, .. } | Self::Structure { line, .. } => Some(*line),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Txt2EpubError {
+    fn from(source: io::Error) -> Self {
+        Self::Io { path: None, source }
+    }
+}
+
+impl From<serde_json::Error> for Txt2EpubError {
+    fn from(error: serde_json::Error) -> Self {
+        io::Error::from(error).into()
+    }
+}
+
+fn path_prefix(path: &Option<PathBuf>) -> String {
+    path.as_ref()
+        .map(|p| format!("{}: ", p.display()))
+        .unwrap_or_default()
+}
@@ -0,0 +1,185 @@
+use std::io::Write;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Serialize;
+use tera::Context;
+
+use crate::{
+    error::{Result, Txt2EpubError},
+    novel_structure::chapter::Line,
+    TEMPLATE_ENGINE,
+};
+
+#[derive(Serialize, Debug, Default)]
+pub struct Fb2Section {
+    /// empty when the novel has no part, the chapters then go straight into the body.
+    pub title: String,
+    pub preface: Vec<String>,
+    pub(crate) chapters: Vec<Fb2Chapter>,
+}
+
+impl Fb2Section {
+    pub fn new(title: String, preface: Vec<String>) -> Self {
+        Self {
+            title,
+            preface,
+            chapters: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Fb2Chapter {
+    pub title: String,
+    pub content: Vec<Line>,
+}
+
+#[derive(Default)]
+pub struct Fb2Builder {
+    id: String,
+    title: String,
+    author: String,
+    /// the language code, without the script or region.
+    lang: String,
+    annotation: Vec<String>,
+    cover: Option<Vec<u8>>,
+    sections: Vec<Fb2Section>,
+}
+
+impl Fb2Builder {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            lang: "zh".into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn metadata(&mut self, title: &str, author: &str, annotation: Vec<String>) -> &mut Self {
+        self.title = title.to_string();
+        self.author = author.to_string();
+        self.annotation = annotation;
+        self
+    }
+
+    /// Set the language from a language tag such as `zh-CN`.
+    pub fn language(&mut self, tag: &str) -> &mut Self {
+        if let Some(lang) = tag.split(['-', '_']).next().filter(|l| !l.is_empty()) {
+            self.lang = lang.to_lowercase();
+        }
+        self
+    }
+
+    /// Set the cover, it must be a jpeg image.
+    pub fn cover(&mut self, cover: Vec<u8>) -> &mut Self {
+        self.cover = Some(cover);
+        self
+    }
+
+    pub fn add_section(&mut self, section: Fb2Section) -> &mut Self {
+        self.sections.push(section);
+        self
+    }
+
+    pub(crate) fn add_chapter(&mut self, chapter: Fb2Chapter) -> &mut Self {
+        if self.sections.is_empty() {
+            self.sections.push(Fb2Section::default());
+        }
+
+        self.sections.last_mut().unwrap().chapters.push(chapter);
+        self
+    }
+
+    pub fn generate<W: Write>(&self, mut out: W) -> Result<()> {
+        let mut context = Context::new();
+        context.insert("id", &self.id);
+        context.insert("title", &self.title);
+        context.insert("author", &self.author);
+        context.insert("lang", &self.lang);
+        context.insert("annotation", &self.annotation);
+        context.insert("cover", &self.cover.as_ref().map(|c| STANDARD.encode(c)));
+        context.insert("sections", &self.sections);
+
+        let fb2 = TEMPLATE_ENGINE
+            .render("fb2", &context)
+            .map_err(|e| Txt2EpubError::template("fb2", e))?;
+
+        out.write_all(fb2.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use roxmltree::Node;
+
+    use crate::{cli::OutputFormat, converter::Converter};
+
+    fn title(node: &Node) -> String {
+        node.children()
+            .find(|n| n.has_tag_name("title"))
+            .and_then(|t| t.first_element_child())
+            .and_then(|p| p.text())
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    fn sections<'a, 'input>(node: Node<'a, 'input>) -> Vec<Node<'a, 'input>> {
+        node.children()
+            .filter(|n| n.has_tag_name("section"))
+            .collect()
+    }
+
+    #[test]
+    fn nested_sections() -> Result<(), Box<dyn Error>> {
+        let mut converter = Converter::new()
+            .format(OutputFormat::Fb2)
+            .meta("书名", "测试")
+            .meta("作者", "某人");
+
+        let book = converter.parse_bytes(
+            "第一卷 开始\n\n第一章 相遇\n\n甲 & 乙。\n\n第二章 别离\n\n第二卷 结束\n\n第一章 重逢\n\n正文三。\n"
+                .as_bytes(),
+        )?;
+
+        let mut out = vec![];
+        converter.write(book, &mut out)?;
+        let fb2 = String::from_utf8(out)?;
+        let doc = roxmltree::Document::parse(&fb2)?;
+
+        let root = doc.root_element();
+        let info = root.descendants().find(|n| n.has_tag_name("title-info"));
+        let book_title = info.and_then(|i| i.children().find(|n| n.has_tag_name("book-title")));
+        assert_eq!(book_title.and_then(|t| t.text()), Some("测试"));
+
+        let body = root.children().find(|n| n.has_tag_name("body")).unwrap();
+        let parts = sections(body);
+        assert_eq!(
+            parts.iter().map(title).collect::<Vec<_>>(),
+            ["第一卷 开始", "第二卷 结束"]
+        );
+
+        let chapters = sections(parts[0]);
+        assert_eq!(
+            chapters.iter().map(title).collect::<Vec<_>>(),
+            ["第一章 相遇", "第二章 别离"]
+        );
+        let paragraphs = chapters[0]
+            .children()
+            .filter(|n| n.has_tag_name("p"))
+            .filter_map(|p| p.text())
+            .collect::<Vec<_>>();
+        assert_eq!(paragraphs, ["甲 & 乙。"]);
+        assert!(chapters[1].children().any(|n| n.has_tag_name("empty-line")));
+
+        assert_eq!(
+            sections(parts[1]).iter().map(title).collect::<Vec<_>>(),
+            ["第一章 重逢"]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,463 @@
+//! The patterns matching the chapter headings.
+//!
+//! A file may mix heading styles, `第12章 标题`, `12. 标题` and `番外一 标题`, so there can be
+//! several chapter patterns. They are tried in order, the first matching one wins.
+//!
+//! A heading regex can name its groups: `(?P<title>…)` is the title, `(?P<no>…)` the number
+//! written in the heading and `(?P<kind>…)` the [`SectionKind`] of a chapter. Without a `title`
+//! group, the first group which is not `no` or `kind` is the title.
+//!
+//! A loose regex also matches content lines, the [`Guard`]s enabled by `--guard` reject those.
+
+use std::{fmt::Display, str::FromStr};
+
+use chinese_number::{ChineseCountMethod, ChineseToNumber};
+use clap::ValueEnum;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+
+/// Headings longer than this, in characters, are most likely a content line.
+pub const DEFAULT_MAX_HEADING_LEN: usize = 30;
+/// A heading ending with one of these is most likely a sentence.
+const SENTENCE_END: &[char] = &['。', '，', '、', '；', ',', ';'];
+
+lazy_static! {
+    static ref HEADING_NO: Regex = Regex::new(
+        r"第\s*([0-9０-９零〇一二两三四五六七八九十百千万壹贰叁肆伍陆柒捌玖拾佰仟]+)\s*[章节回卷部]"
+    )
+    .unwrap();
+    /// `NAME[:KIND]=REGEX` of `--chapter-regex`.
+    static ref NAMED_PATTERN: Regex = Regex::new(r"^([A-Za-z][\w-]*)(?::([a-z]+))?=(.+)$").unwrap();
+}
+
+/// What a chapter is in the novel, only `chapter` headings are numbered in the titles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SectionKind {
+    /// a numbered chapter
+    #[default]
+    Chapter,
+    /// 楔子, 序章
+    Prologue,
+    /// 间章, 幕间
+    Interlude,
+    /// 尾声, 终章
+    Epilogue,
+    /// 番外
+    Extra,
+    /// 后记
+    Afterword,
+}
+
+impl SectionKind {
+    /// The kind captured by a `kind` group, its english name or a chinese or japanese word.
+    pub fn from_heading(s: &str) -> Option<Self> {
+        let s = s.trim();
+
+        if let Ok(kind) = <Self as ValueEnum>::from_str(s, true) {
+            return Some(kind);
+        }
+
+        match s {
+            "章" | "回" | "节" | "節" | "话" | "話" => Some(SectionKind::Chapter),
+            "楔子" | "引子" | "序" | "序章" | "序幕" => Some(SectionKind::Prologue),
+            "间章" | "間章" | "幕间" | "幕間" => Some(SectionKind::Interlude),
+            "尾声" | "尾聲" | "终章" | "終章" => Some(SectionKind::Epilogue),
+            "番外" | "外传" | "外傳" => Some(SectionKind::Extra),
+            "后记" | "後記" | "あとがき" => Some(SectionKind::Afterword),
+            _ => None,
+        }
+    }
+}
+
+impl Display for SectionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChapterPattern {
+    /// shown in the parsed novel dump next to each heading it matched.
+    pub name: String,
+    pub kind: SectionKind,
+    /// the title is its `title` group, or its first group other than `no` and `kind`.
+    pub regex: Regex,
+}
+
+impl ChapterPattern {
+    pub fn new(name: impl Into<String>, regex: Regex) -> Self {
+        Self {
+            name: name.into(),
+            kind: SectionKind::Chapter,
+            regex,
+        }
+    }
+
+    pub fn kind(mut self, kind: SectionKind) -> Self {
+        self.kind = kind;
+        self
+    }
+}
+
+/// `NAME[:KIND]=REGEX`, or just `REGEX` which leaves the name empty.
+impl FromStr for ChapterPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(cap) = NAMED_PATTERN.captures(s) else {
+            let regex = Regex::new(s).map_err(|_| "Invalid regex".to_string())?;
+            return Ok(ChapterPattern::new("", regex));
+        };
+
+        let regex = Regex::new(&cap[3]).map_err(|_| "Invalid regex".to_string())?;
+        let kind = match cap.get(2) {
+            Some(kind) => SectionKind::from_str(kind.as_str(), false)?,
+            None => SectionKind::Chapter,
+        };
+
+        Ok(ChapterPattern::new(&cap[1], regex).kind(kind))
+    }
+}
+
+/// What a heading regex captured.
+pub(crate) struct HeadingMatch<'l> {
+    pub title: &'l str,
+    /// the number written in the heading, from the `no` group.
+    pub no: Option<usize>,
+    /// from the `kind` group, `None` if it names no kind.
+    pub kind: Option<SectionKind>,
+}
+
+impl<'l> HeadingMatch<'l> {
+    fn new(regex: &Regex, cap: &Captures<'l>) -> Self {
+        Self {
+            title: title_group(regex)
+                .and_then(|i| cap.get(i))
+                .map_or("", |m| m.as_str()),
+            no: cap.name("no").and_then(|m| parse_no(m.as_str())),
+            kind: cap
+                .name("kind")
+                .and_then(|m| SectionKind::from_heading(m.as_str())),
+        }
+    }
+}
+
+pub(crate) fn match_heading<'l>(regex: &Regex, line: &'l str) -> Option<HeadingMatch<'l>> {
+    regex
+        .captures(line)
+        .map(|cap| HeadingMatch::new(regex, &cap))
+}
+
+/// The first pattern matching the trimmed line, with what it captured.
+pub(crate) fn match_chapter<'a, 'l>(
+    patterns: &'a [ChapterPattern],
+    line: &'l str,
+) -> Option<(&'a ChapterPattern, HeadingMatch<'l>)> {
+    patterns
+        .iter()
+        .find_map(|p| match_heading(&p.regex, line).map(|heading| (p, heading)))
+}
+
+pub(crate) fn is_chapter(patterns: &[ChapterPattern], line: &str) -> bool {
+    patterns.iter().any(|p| p.regex.is_match(line))
+}
+
+/// The index of the group capturing the title.
+fn title_group(regex: &Regex) -> Option<usize> {
+    let names = regex.capture_names().collect::<Vec<_>>();
+
+    names
+        .iter()
+        .position(|name| *name == Some("title"))
+        .or_else(|| {
+            names
+                .iter()
+                .skip(1)
+                .position(|name| !matches!(name, Some("no" | "kind")))
+                .map(|i| i + 1)
+        })
+}
+
+/// Check the groups of a heading regex, `kind` is only allowed for the chapters.
+pub(crate) fn check_groups(regex: &Regex, chapter: bool) -> Result<(), String> {
+    for name in regex.capture_names().flatten() {
+        match name {
+            "no" | "title" => {}
+            "kind" if chapter => {}
+            "kind" => {
+                return Err("parts have no kind, the `kind` group is for chapter regexes".into())
+            }
+            name => {
+                return Err(format!(
+                    "unknown group `{}`, the named groups are no, title and kind",
+                    name
+                ))
+            }
+        }
+    }
+
+    if title_group(regex).is_some() {
+        return Ok(());
+    }
+
+    if regex.captures_len() == 1 {
+        Err("the regex captures no title, add a group such as `(.*)` or `(?P<title>.*)`".into())
+    } else {
+        let names = regex
+            .capture_names()
+            .flatten()
+            .map(|name| format!("`{}`", name))
+            .collect::<Vec<_>>();
+
+        Err(format!(
+            "the regex captures {} but no title, add a `(?P<title>.*)` group",
+            names.join(" and ")
+        ))
+    }
+}
+
+/// The chapter number in a heading like `第十二章 标题` or `第12章`.
+pub(crate) fn heading_no(raw_title: &str) -> Option<usize> {
+    parse_no(HEADING_NO.captures(raw_title)?.get(1)?.as_str())
+}
+
+/// The number of a heading, in arabic, full width, chinese or roman numerals.
+pub(crate) fn parse_no(no: &str) -> Option<usize> {
+    let no = no
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '０'..='９' => char::from_u32(c as u32 - '０' as u32 + '0' as u32).unwrap(),
+            '两' | '兩' => '二',
+            c => c,
+        })
+        .collect::<String>();
+
+    no.parse()
+        .ok()
+        .or_else(|| no.to_number(ChineseCountMethod::TenThousand).ok())
+        .or_else(|| parse_roman(&no))
+}
+
+fn parse_roman(no: &str) -> Option<usize> {
+    let value = |c: char| match c.to_ascii_uppercase() {
+        'I' => Some(1),
+        'V' => Some(5),
+        'X' => Some(10),
+        'L' => Some(50),
+        'C' => Some(100),
+        'D' => Some(500),
+        'M' => Some(1000),
+        _ => None,
+    };
+
+    let values = no.chars().map(value).collect::<Option<Vec<usize>>>()?;
+    let mut total = 0;
+
+    for (i, v) in values.iter().enumerate() {
+        match values.get(i + 1) {
+            Some(next) if next > v => total -= *v as isize,
+            _ => total += *v as isize,
+        }
+    }
+
+    (total > 0).then_some(total as usize)
+}
+
+/// The regexes of the patterns, for the report and the error messages.
+pub(crate) fn describe(patterns: &[ChapterPattern]) -> String {
+    patterns
+        .iter()
+        .map(|p| p.regex.as_str())
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+/// A check telling a heading from a content line matched by a loose regex, such as
+/// `第三章的内容他已经忘了`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Guard {
+    /// headings longer than `--max-heading-len` characters
+    MaxLen,
+    /// headings not surrounded by blank lines, part headings and the ends of the file count as
+    /// blank lines
+    BlankLines,
+    /// headings ending with sentence punctuation, such as `。` or `，`
+    Punctuation,
+    /// headings numbered lower than or as the previous one
+    Monotonic,
+}
+
+/// The guards enabled by `--guard`, none by default.
+#[derive(Debug, Clone)]
+pub struct HeadingGuards {
+    pub guards: Vec<Guard>,
+    pub max_len: usize,
+}
+
+impl Default for HeadingGuards {
+    fn default() -> Self {
+        Self {
+            guards: vec![],
+            max_len: DEFAULT_MAX_HEADING_LEN,
+        }
+    }
+}
+
+impl HeadingGuards {
+    pub fn enabled(&self, guard: Guard) -> bool {
+        self.guards.contains(&guard)
+    }
+
+    /// Whether the guards look at the line after a heading.
+    pub(crate) fn need_next_line(&self) -> bool {
+        self.enabled(Guard::BlankLines)
+    }
+
+    pub(crate) fn check(&self) -> GuardCheck<'_> {
+        GuardCheck {
+            guards: self,
+            last_no: None,
+        }
+    }
+}
+
+/// Checks the headings of a part, or of the parts of a novel, in order.
+pub(crate) struct GuardCheck<'a> {
+    guards: &'a HeadingGuards,
+    /// the number of the last accepted heading.
+    last_no: Option<usize>,
+}
+
+impl GuardCheck<'_> {
+    /// Why the trimmed line is not a heading, `None` if it is one.
+    pub(crate) fn reject(
+        &mut self,
+        line: &str,
+        heading: &HeadingMatch,
+        blank_before: bool,
+        blank_after: bool,
+    ) -> Option<String> {
+        let guards = self.guards;
+        let len = line.chars().count();
+        let no = heading.no.or_else(|| heading_no(line));
+
+        if guards.enabled(Guard::MaxLen) && len > guards.max_len {
+            return Some(format!("longer than {} characters", guards.max_len));
+        }
+
+        if guards.enabled(Guard::BlankLines) && !(blank_before && blank_after) {
+            return Some("not surrounded by blank lines".into());
+        }
+
+        if guards.enabled(Guard::Punctuation) && line.ends_with(SENTENCE_END) {
+            return Some("ends with sentence punctuation".into());
+        }
+
+        if guards.enabled(Guard::Monotonic) {
+            if let (Some(no), Some(last)) = (no, self.last_no) {
+                if no <= last {
+                    return Some(format!("numbered {} after {}", no, last));
+                }
+            }
+        }
+
+        self.last_no = no.or(self.last_no);
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::{
+        check_groups, heading_no, match_chapter, match_heading, ChapterPattern, Guard,
+        HeadingGuards, SectionKind,
+    };
+
+    #[test]
+    fn patterns() {
+        let patterns = [
+            "^第.+章 (.*)$",
+            "dotted=^\\d+\\. (.*)$",
+            "extra:extra=^(番外.*)$",
+        ]
+        .map(|s| s.parse::<ChapterPattern>().unwrap());
+
+        assert_eq!(patterns[0].name, "");
+        assert_eq!(patterns[1].name, "dotted");
+        assert_eq!(patterns[2].kind, SectionKind::Extra);
+
+        let (pattern, heading) = match_chapter(&patterns, "12. 标题").unwrap();
+        assert_eq!((pattern.name.as_str(), heading.title), ("dotted", "标题"));
+
+        let (pattern, heading) = match_chapter(&patterns, "番外一 标题").unwrap();
+        assert_eq!(
+            (pattern.kind, heading.title),
+            (SectionKind::Extra, "番外一 标题")
+        );
+
+        assert!(match_chapter(&patterns, "正文").is_none());
+        assert!("name:unknown=(.*)".parse::<ChapterPattern>().is_err());
+    }
+
+    #[test]
+    fn heading_numbers() {
+        assert_eq!(heading_no("第十二章 标题"), Some(12));
+        assert_eq!(heading_no("第一百零五章"), Some(105));
+        assert_eq!(heading_no("第两百章"), Some(200));
+        assert_eq!(heading_no("第１２章 标题"), Some(12));
+        assert_eq!(heading_no("第37章"), Some(37));
+        assert_eq!(heading_no("楔子"), None);
+    }
+
+    #[test]
+    fn guards() {
+        let regex = Regex::new(r"^第.+章(.*)$").unwrap();
+        let guards = HeadingGuards {
+            guards: vec![
+                Guard::MaxLen,
+                Guard::BlankLines,
+                Guard::Punctuation,
+                Guard::Monotonic,
+            ],
+            max_len: 12,
+        };
+        let mut check = guards.check();
+        let mut reject = |line: &str, blank: bool| {
+            check.reject(line, &match_heading(&regex, line).unwrap(), blank, blank)
+        };
+
+        assert_eq!(reject("第三章 标题", true), None);
+        assert!(reject("第四章 标题", false).is_some());
+        assert!(reject("第三章的内容他已经忘了。", true).is_some());
+        assert!(reject("第五章写得很长很长很长很长很长", true).is_some());
+        assert!(reject("第二章 标题", true).is_some());
+        assert_eq!(reject("第四章 标题", true), None);
+    }
+
+    #[test]
+    fn named_groups() {
+        let regex = Regex::new(r"^第(?P<no>.+)(?P<kind>章|番外) (.*)$").unwrap();
+        assert!(check_groups(&regex, true).is_ok());
+        assert!(check_groups(&regex, false).is_err());
+
+        let heading = match_heading(&regex, "第十二章 标题").unwrap();
+        assert_eq!(heading.title, "标题");
+        assert_eq!(heading.no, Some(12));
+        assert_eq!(heading.kind, Some(SectionKind::Chapter));
+
+        let regex = Regex::new(r"^Chapter (?P<no>\w+): (?P<title>.*)$").unwrap();
+        let heading = match_heading(&regex, "Chapter XIV: Title").unwrap();
+        assert_eq!((heading.title, heading.no), ("Title", Some(14)));
+
+        assert!(check_groups(&Regex::new(r"^第(?P<no>.+)章").unwrap(), true).is_err());
+        assert!(check_groups(&Regex::new(r"^第.+章 (?P<name>.*)$").unwrap(), true).is_err());
+        assert!(check_groups(&Regex::new(r"^第.+章").unwrap(), true).is_err());
+    }
+}
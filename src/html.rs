@@ -0,0 +1,199 @@
+use std::{fs, io::Write, path::Path};
+
+use serde::Serialize;
+use tera::Context;
+
+use crate::{
+    error::{Result, Txt2EpubError},
+    locale::Locale,
+    NOVEL_CSS, NOVEL_HTML_CSS, TEMPLATE_ENGINE,
+};
+
+/// A rendered page of the book, shared by the single file and the site output.
+#[derive(Serialize, Debug)]
+pub struct HtmlContent {
+    /// used as the anchor in single file output and as the file stem in site output.
+    pub id: String,
+    pub title: String,
+    pub level: usize,
+    /// the rendered body fragment, without the `<html>` wrapper.
+    pub body: String,
+}
+
+impl HtmlContent {
+    pub fn new(id: impl Into<String>, body: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            title: String::new(),
+            level: 1,
+            body: body.into(),
+        }
+    }
+
+    pub fn title(mut self, title: impl Into<String>) -> Self {
+        self.title = title.into();
+        self
+    }
+
+    pub fn level(mut self, level: usize) -> Self {
+        self.level = level;
+        self
+    }
+}
+
+#[derive(Default)]
+pub struct HtmlBuilder {
+    title: String,
+    author: String,
+    lang: String,
+    toc_name: String,
+    contents: Vec<HtmlContent>,
+}
+
+impl HtmlBuilder {
+    pub fn new() -> Self {
+        Self {
+            lang: Locale::default().tag().to_string(),
+            toc_name: Locale::default().toc_name().to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn metadata(&mut self, title: &str, author: &str) -> &mut Self {
+        self.title = title.to_string();
+        self.author = author.to_string();
+        self
+    }
+
+    /// The `lang` of the pages and the title of the TOC.
+    pub fn language(&mut self, lang: &str, toc_name: &str) -> &mut Self {
+        self.lang = lang.to_string();
+        self.toc_name = toc_name.to_string();
+        self
+    }
+
+    pub fn add_content(&mut self, content: HtmlContent) -> &mut Self {
+        self.contents.push(content);
+        self
+    }
+
+    /// Render the whole book into one self-contained html file with a TOC sidebar.
+    pub fn generate<W: Write>(&self, mut out: W) -> Result<()> {
+        let mut context = Context::new();
+        context.insert("title", &self.title);
+        context.insert("author", &self.author);
+        context.insert("lang", &self.lang);
+        context.insert("toc_name", &self.toc_name);
+        context.insert("css", NOVEL_CSS);
+        context.insert("html_css", NOVEL_HTML_CSS);
+        context.insert("contents", &self.contents);
+
+        out.write_all(render("book", &context)?.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Render the book into `dir`, one page per content with prev/next navigation.
+    ///
+    /// Returns the total size of the written files.
+    pub fn generate_site(&self, dir: &Path) -> Result<u64> {
+        let mut size = 0;
+        let mut write = |name: &str, content: String| {
+            let path = dir.join(name);
+            size += content.len() as u64;
+            fs::write(&path, content).map_err(|e| Txt2EpubError::io(path, e))
+        };
+
+        fs::create_dir_all(dir).map_err(|e| Txt2EpubError::io(dir, e))?;
+        write("stylesheet.css", [NOVEL_CSS, NOVEL_HTML_CSS].join("\n"))?;
+
+        let mut context = Context::new();
+        context.insert("title", &self.title);
+        context.insert("author", &self.author);
+        context.insert("lang", &self.lang);
+        context.insert("toc_name", &self.toc_name);
+        context.insert("contents", &self.contents);
+
+        write("index.html", render("site_index", &context)?)?;
+
+        for (i, content) in self.contents.iter().enumerate() {
+            let mut context = Context::new();
+            context.insert("title", &self.title);
+            context.insert("lang", &self.lang);
+            context.insert("toc_name", &self.toc_name);
+            context.insert("content", content);
+            context.insert("prev", &i.checked_sub(1).map(|i| &self.contents[i]));
+            context.insert("next", &self.contents.get(i + 1));
+
+            write(
+                &format!("{}.html", content.id),
+                render("site_page", &context)?,
+            )?;
+        }
+
+        Ok(size)
+    }
+}
+
+fn render(template: &str, context: &Context) -> Result<String> {
+    TEMPLATE_ENGINE
+        .render(template, context)
+        .map_err(|e| Txt2EpubError::template(template, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use crate::{cli::OutputFormat, converter::Converter};
+
+    #[test]
+    fn single_file() -> Result<(), Box<dyn Error>> {
+        let mut converter = Converter::new()
+            .format(OutputFormat::Html)
+            .meta("书名", "测试")
+            .meta("作者", "某人");
+
+        let book = converter.parse_bytes(
+            "第一卷 开始\n\n第一章 相遇\n\n正文一。\n\n第二章 别离\n\n正文二。\n".as_bytes(),
+        )?;
+
+        let mut out = vec![];
+        converter.write(book, &mut out)?;
+        let html = String::from_utf8(out)?;
+
+        assert!(html.starts_with("<!DOCTYPE html>\n<html lang=\"zh-CN\">"));
+        assert!(html.contains("<title>测试</title>"));
+
+        let toc = &html[html.find("<nav class=\"toc\">").unwrap()..html.find("</nav>").unwrap()];
+        assert!(toc.contains("<h2 class=\"title\">目录</h2>"));
+        assert!(toc.contains(r##"<li class="toc-level-1"><a href="#intro">简介</a></li>"##));
+        assert!(
+            toc.contains(r##"<li class="toc-level-1"><a href="#part-01">第一卷 开始</a></li>"##)
+        );
+        assert!(toc.contains(
+            r##"<li class="toc-level-2"><a href="#chapter-01-0001">第一章 相遇</a></li>"##
+        ));
+        assert!(toc.contains(
+            r##"<li class="toc-level-2"><a href="#chapter-01-0002">第二章 别离</a></li>"##
+        ));
+
+        let sections = html.match_indices("<section id=").map(|(i, _)| i);
+        let ids = sections
+            .map(|i| html[i..].split('"').nth(1).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            ["intro", "part-01", "chapter-01-0001", "chapter-01-0002"]
+        );
+
+        let first = &html[html.find("id=\"chapter-01-0001\"").unwrap()..];
+        let first = &first[..first.find("</section>").unwrap()];
+        assert!(first.contains("卷一 · 一"));
+        assert!(first.contains("<h2 class=\"title\">相遇</h2>"));
+        assert!(first.contains("<p class=\"content\">正文一。</p>"));
+        assert!(!first.contains("正文二。"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use crate::epub::escape;
+
+/// Characters that end a sentence.
+const TERMINATORS: &[char] = &['。', '！', '？', '…', '!', '?', '.'];
+
+/// Characters that still belong to the sentence after its terminator, e.g. `“好。”`.
+const CLOSINGS: &[char] = &['」', '』', '”', '’', '）', '】', '》', '"', '\'', ')', ']'];
+
+/// Split a line into sentences, keeping the terminators, closing quotes and the trailing
+/// whitespace with the sentence they end.
+pub fn split_sentences(text: &str) -> Vec<&str> {
+    let mut sentences = vec![];
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if !TERMINATORS.contains(&c) {
+            continue;
+        }
+
+        // `.` only ends a sentence before a space, a closing or the end of line, so `3.14` stays.
+        if c == '.'
+            && chars.peek().is_some_and(|&(_, n)| {
+                !n.is_whitespace() && !TERMINATORS.contains(&n) && !CLOSINGS.contains(&n)
+            })
+        {
+            continue;
+        }
+
+        let mut end = i + c.len_utf8();
+
+        while let Some(&(j, n)) = chars.peek() {
+            if TERMINATORS.contains(&n) || CLOSINGS.contains(&n) || n.is_whitespace() {
+                end = j + n.len_utf8();
+                chars.next();
+            } else {
+                break;
+            }
+        }
+
+        sentences.push(&text[start..end]);
+        start = end;
+    }
+
+    if start < text.len() {
+        sentences.push(&text[start..]);
+    }
+
+    sentences
+}
+
+/// Wrap every sentence of the `para`th paragraph into a `koboSpan`, escaping its text.
+pub fn kobo_spans(text: &str, para: u64) -> String {
+    split_sentences(text)
+        .into_iter()
+        .enumerate()
+        .map(|(i, sentence)| {
+            format!(
+                r#"<span class="koboSpan" id="kobo.{}.{}">{}</span>"#,
+                para,
+                i + 1,
+                escape(sentence)
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_sentences;
+
+    #[test]
+    fn split_cjk_and_western() {
+        assert_eq!(
+            split_sentences("他说：“好。”然后走了！真的吗？……"),
+            vec!["他说：“好。”", "然后走了！", "真的吗？……"]
+        );
+        assert_eq!(
+            split_sentences("It costs 3.14 dollars. Really?! No"),
+            vec!["It costs 3.14 dollars. ", "Really?! ", "No"]
+        );
+    }
+}
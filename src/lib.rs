@@ -1,46 +1,83 @@
 #![feature(path_file_prefix)]
 
-use std::{
-    fs::File,
-    io::{BufReader, Cursor},
-    time::SystemTime,
-};
+use std::{collections::HashMap, io::Cursor};
 
-use ::log::{debug, info};
+use ::log::debug;
 use chinese_number::{ChineseCase, ChineseCountMethod, ChineseVariant, NumberToChinese};
 use colored::Colorize;
-use epub_builder::{EpubBuilder, ZipLibrary};
 use image::ImageOutputFormat;
 use lazy_static::lazy_static;
 use novel_structure::chapter::Line;
-use tera::{Tera, Value};
+use serde::Serialize;
+use tera::{Context, Tera, Value};
 
-use cli::ConvertOpt;
-use error::AnyError;
+use cli::{ConvertOpt, OutputFormat};
+use epub::EpubSink;
+use error::{Result, Txt2EpubError};
+use fb2::Fb2Builder;
+use html::HtmlBuilder;
+use txt::TxtBuilder;
 
-use crate::{epub::EpubFactory, parse::parse_txt};
+use crate::converter::{ConvertResult, Converter};
 
+pub mod batch;
+pub mod cache;
+pub mod cleanup;
 pub mod cli;
+pub mod converter;
 pub mod epub;
 pub mod error;
+pub mod fb2;
+pub mod heading;
+pub mod html;
+pub mod kepub;
+pub mod lint;
+pub mod locale;
 pub mod log;
+pub mod model;
 pub mod novel_structure;
 pub mod parse;
+pub mod stream;
+pub mod title_format;
+pub mod txt;
+pub mod validate;
 
 static NOVEL_PART_TEMPLATE: &str = include_str!("templates/part.html");
 static NOVEL_CHAPTER_TEMPLATE: &str = include_str!("templates/chapter.html");
 static NOVEL_INTRO_TEMPLATE: &str = include_str!("templates/intro.html");
+static NOVEL_PART_BODY_TEMPLATE: &str = include_str!("templates/part_body.html");
+static NOVEL_CHAPTER_BODY_TEMPLATE: &str = include_str!("templates/chapter_body.html");
+static NOVEL_INTRO_BODY_TEMPLATE: &str = include_str!("templates/intro_body.html");
+static NOVEL_BOOK_TEMPLATE: &str = include_str!("templates/book.html");
+static NOVEL_SITE_INDEX_TEMPLATE: &str = include_str!("templates/site_index.html");
+static NOVEL_SITE_PAGE_TEMPLATE: &str = include_str!("templates/site_page.html");
+static NOVEL_FB2_TEMPLATE: &str = include_str!("templates/book.fb2");
+static EPUB_OPF_TEMPLATE: &str = include_str!("templates/content.opf");
+static EPUB_NCX_TEMPLATE: &str = include_str!("templates/toc.ncx");
+static EPUB_NAV_TEMPLATE: &str = include_str!("templates/nav.xhtml");
 
 pub static NOVEL_CSS: &str = include_str!("templates/stylesheet.css");
+pub static NOVEL_HTML_CSS: &str = include_str!("templates/html.css");
 
 lazy_static! {
     pub static ref TEMPLATE_ENGINE: Tera = {
         let mut tera = Tera::default();
-        tera.add_raw_template("part", NOVEL_PART_TEMPLATE).unwrap();
-        tera.add_raw_template("chapter", NOVEL_CHAPTER_TEMPLATE)
-            .unwrap();
-        tera.add_raw_template("intro", NOVEL_INTRO_TEMPLATE)
-            .unwrap();
+        tera.add_raw_templates(vec![
+            ("part_body", NOVEL_PART_BODY_TEMPLATE),
+            ("chapter_body", NOVEL_CHAPTER_BODY_TEMPLATE),
+            ("intro_body", NOVEL_INTRO_BODY_TEMPLATE),
+            ("part", NOVEL_PART_TEMPLATE),
+            ("chapter", NOVEL_CHAPTER_TEMPLATE),
+            ("intro", NOVEL_INTRO_TEMPLATE),
+            ("book", NOVEL_BOOK_TEMPLATE),
+            ("site_index", NOVEL_SITE_INDEX_TEMPLATE),
+            ("site_page", NOVEL_SITE_PAGE_TEMPLATE),
+            ("fb2", NOVEL_FB2_TEMPLATE),
+            ("opf", EPUB_OPF_TEMPLATE),
+            ("ncx", EPUB_NCX_TEMPLATE),
+            ("nav", EPUB_NAV_TEMPLATE),
+        ])
+        .unwrap();
 
         tera.register_filter("to_chinese_string", |value: &Value, _: &_| {
             if let Some(no) = value.as_u64() {
@@ -57,6 +94,18 @@ lazy_static! {
             Ok(value.clone())
         });
 
+        tera.register_filter(
+            "kobo_spans",
+            |value: &Value, args: &HashMap<String, Value>| {
+                if let Some(line) = value.as_str() {
+                    let para = args.get("para").and_then(Value::as_u64).unwrap_or(0);
+                    return Ok(Value::String(kepub::kobo_spans(line, para)));
+                }
+
+                Ok(value.clone())
+            },
+        );
+
         tera.register_filter("to_tradition_chinese_string", |value: &Value, _: &_| {
             if let Some(no) = value.as_u64() {
                 return Ok(Value::String(
@@ -76,54 +125,96 @@ lazy_static! {
     };
 }
 
-pub type EpubBuilderMut<'a> = &'a mut EpubBuilder<ZipLibrary>;
-
 pub(crate) trait WriteToEpub {
-    fn write_to_epub<'a>(
+    fn write_to_epub<'a, E: EpubSink>(
         self,
-        epub: EpubBuilderMut<'a>,
+        epub: &'a mut E,
         options: &mut ConvertOpt,
-    ) -> Result<EpubBuilderMut<'a>, AnyError>;
+    ) -> Result<&'a mut E>;
 }
 
-pub(crate) fn get_cover_image(url: &str) -> Result<Vec<u8>, AnyError> {
-    debug!("fetching cover image.");
+pub type HtmlBuilderMut<'a> = &'a mut HtmlBuilder;
 
-    let response = reqwest::blocking::get(url)?;
-    let mut image = vec![];
+pub(crate) trait WriteToHtml {
+    fn write_to_html<'a>(
+        self,
+        html: HtmlBuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<HtmlBuilderMut<'a>>;
+}
 
-    image::load_from_memory(&response.bytes()?)?
-        .write_to(&mut Cursor::new(&mut image), ImageOutputFormat::Jpeg(100))?;
+pub type Fb2BuilderMut<'a> = &'a mut Fb2Builder;
 
-    debug!("successfully fetched cover image.");
-    debug!("size: {:.3}KB", image.len() as f64 / 1024.0);
+pub(crate) trait WriteToFb2 {
+    fn write_to_fb2<'a>(
+        self,
+        fb2: Fb2BuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<Fb2BuilderMut<'a>>;
+}
 
-    Ok(image)
+pub type TxtBuilderMut<'a> = &'a mut TxtBuilder;
+
+pub(crate) trait WriteToTxt {
+    fn write_to_txt<'a>(
+        self,
+        txt: TxtBuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<TxtBuilderMut<'a>>;
 }
 
-pub fn txt2epub(mut opt: ConvertOpt) {
-    if let Err(err) = txt2epub_inner(&mut opt) {
-        ::log::error!("Failed to convert {}. Due to: ", opt.path.display());
-        ::log::error!("{}\n", err.to_string().on_red());
-    }
+/// Render one of the book templates, with the output options every template can branch on.
+pub(crate) fn render_template<T: Serialize>(
+    template: &str,
+    value: T,
+    opt: &ConvertOpt,
+) -> Result<String> {
+    let mut context =
+        Context::from_serialize(value).map_err(|e| Txt2EpubError::template(template, e))?;
+
+    context.insert("kepub", &(opt.format == OutputFormat::Kepub));
+    context.insert("epub_version", &(opt.epub_version as u8));
+    context.insert("heading_style", &opt.heading_style);
+
+    TEMPLATE_ENGINE
+        .render(template, &context)
+        .map_err(|e| Txt2EpubError::template(template, e))
 }
 
-fn txt2epub_inner(opt: &mut ConvertOpt) -> Result<(), AnyError> {
-    info!("converting `{}`.", opt.path.display());
+pub(crate) fn get_cover_image(url: &str) -> Result<Vec<u8>> {
+    debug!("fetching cover image.");
+
+    let cover_error = |e: &dyn std::error::Error| Txt2EpubError::Cover {
+        url: url.to_string(),
+        message: e.to_string(),
+    };
+
+    let bytes = reqwest::blocking::get(url)
+        .and_then(|r| r.error_for_status())
+        .and_then(|r| r.bytes())
+        .map_err(|e| cover_error(&e))?;
+    let mut image = vec![];
 
-    let start = SystemTime::now();
+    image::load_from_memory(&bytes)
+        .and_then(|i| i.write_to(&mut Cursor::new(&mut image), ImageOutputFormat::Jpeg(100)))
+        .map_err(|e| cover_error(&e))?;
 
-    let mut epub = EpubFactory::with_default_css()?.into();
+    debug!("successfully fetched cover image.");
+    debug!("size: {:.3}KB", image.len() as f64 / 1024.0);
 
-    parse_txt(&mut BufReader::new(File::open(&opt.path)?), opt)?
-        .write_to_epub(&mut epub, opt)?
-        .generate(File::create(&opt.out_file)?)?;
+    Ok(image)
+}
 
-    info!("saving file to {}", opt.out_file.display());
-    info!("finish converting {}.", opt.path.display());
-    info!("cost {}s.\n", start.elapsed()?.as_secs_f32());
+pub fn txt2epub(opt: ConvertOpt) -> Result<ConvertResult> {
+    let mut converter = Converter::from(opt);
 
-    Ok(())
+    converter.convert_file().inspect_err(|err| {
+        ::log::error!(
+            "Failed to convert {}. Due to: ",
+            converter.options().path.display()
+        );
+        ::log::error!("{}\n", err.to_string().on_red());
+    })
 }
 
 pub(crate) fn quote_replace(s: &mut String) {
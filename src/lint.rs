@@ -0,0 +1,410 @@
+//! Structural checks over the parsed [`Book`], run by the `lint` subcommand and before writing.
+
+use std::{collections::HashMap, fmt::Display};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{
+    cleanup::is_ad,
+    heading::{heading_no, SectionKind},
+    model::{Book, BookChapter},
+};
+
+/// Titles longer than this, in characters, are most likely a content line matched as a heading.
+const MAX_TITLE_LEN: usize = 30;
+/// A chapter shorter than median / 10 or longer than median * 5 is an outlier.
+const OUTLIER_FACTOR: usize = 10;
+const OUTLIER_MAX_FACTOR: usize = 5;
+/// Lengths are only compared when the book has at least this many chapters.
+const OUTLIER_MIN_CHAPTERS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rule {
+    /// chapter numbers in the headings are not consecutive
+    Numbering,
+    /// two chapters share the same title
+    DuplicateTitle,
+    /// a chapter is much shorter or longer than the others
+    OutlierLength,
+    /// a chapter without content
+    EmptyChapter,
+    /// a part without chapter
+    EmptyPart,
+    /// a title too long to be a heading
+    LongTitle,
+    /// a line looks like an ad of the site the text comes from
+    Ads,
+    /// both “” and 「」 quotes are used
+    MixedQuotes,
+}
+
+impl Rule {
+    pub fn default_severity(&self) -> Severity {
+        Severity::Warn
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Allow,
+    Warn,
+    Deny,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
+
+/// The severity of each rule, rules not set use [`Rule::default_severity`].
+#[derive(Debug, Clone, Default)]
+pub struct LintOpt {
+    pub levels: HashMap<Rule, Severity>,
+    /// turn every warning into an error.
+    pub deny_warnings: bool,
+}
+
+impl LintOpt {
+    pub fn severity(&self, rule: Rule) -> Severity {
+        match self
+            .levels
+            .get(&rule)
+            .copied()
+            .unwrap_or_else(|| rule.default_severity())
+        {
+            Severity::Warn if self.deny_warnings => Severity::Deny,
+            severity => severity,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Issue {
+    pub rule: Rule,
+    pub severity: Severity,
+    /// line number in the source, 0 if the issue is about the whole book.
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {}[{}]: {}",
+            self.line, self.severity, self.rule, self.message
+        )
+    }
+}
+
+/// Check the book, issues of allowed rules are left out.
+pub fn lint(book: &Book, opt: &LintOpt) -> Vec<Issue> {
+    let mut issues = vec![];
+    let mut push = |rule: Rule, line: usize, message: String| {
+        let severity = opt.severity(rule);
+
+        if severity != Severity::Allow {
+            issues.push(Issue {
+                rule,
+                severity,
+                line,
+                message,
+            });
+        }
+    };
+
+    let chapters = book
+        .parts
+        .iter()
+        .flat_map(|p| &p.chapters)
+        .collect::<Vec<_>>();
+
+    if book.has_parts {
+        for part in book.parts.iter().filter(|p| p.chapters.is_empty()) {
+            push(
+                Rule::EmptyPart,
+                part.line,
+                format!("part `{}` has no chapter", part.title),
+            );
+        }
+    }
+
+    check_numbering(&chapters, &mut push);
+
+    let mut titles = HashMap::new();
+
+    for chapter in &chapters {
+        if chapter.lines.is_empty() {
+            push(
+                Rule::EmptyChapter,
+                chapter.line,
+                format!("chapter `{}` is empty", chapter.title),
+            );
+        }
+
+        let title = chapter.title.trim();
+
+        if title.chars().count() > MAX_TITLE_LEN {
+            push(
+                Rule::LongTitle,
+                chapter.line,
+                format!(
+                    "title of {} characters, is it a content line?",
+                    title.chars().count()
+                ),
+            );
+        }
+
+        if !title.is_empty() {
+            match titles.get(title) {
+                Some(first) => push(
+                    Rule::DuplicateTitle,
+                    chapter.line,
+                    format!("title `{}` is already used at line {}", title, first),
+                ),
+                None => {
+                    titles.insert(title, chapter.line);
+                }
+            }
+        }
+
+        for line in chapter.lines.iter().filter(|l| is_ad(&l.content)) {
+            push(
+                Rule::Ads,
+                line.line,
+                format!("looks like an ad, `--clean` removes it: `{}`", line.content),
+            );
+        }
+    }
+
+    check_length(&chapters, &mut push);
+    check_quotes(&chapters, &mut push);
+
+    issues.sort_by_key(|i| i.line);
+    issues
+}
+
+/// Chapter numbers must go up by one, restarting from 1 is fine, e.g. at a new part. Prologues,
+/// extras and the other kinds are numbered on their own.
+fn check_numbering(chapters: &[&BookChapter], push: &mut impl FnMut(Rule, usize, String)) {
+    let mut last = None;
+
+    for chapter in chapters.iter().filter(|c| c.kind == SectionKind::Chapter) {
+        let Some(no) = chapter
+            .heading_no
+            .or_else(|| heading_no(&chapter.raw_title))
+        else {
+            continue;
+        };
+
+        if let Some(last) = last {
+            if no != 1 && no != last + 1 {
+                push(
+                    Rule::Numbering,
+                    chapter.line,
+                    format!("chapter number jumps from {} to {}", last, no),
+                );
+            }
+        }
+
+        last = Some(no);
+    }
+}
+
+fn check_length(chapters: &[&BookChapter], push: &mut impl FnMut(Rule, usize, String)) {
+    if chapters.len() < OUTLIER_MIN_CHAPTERS {
+        return;
+    }
+
+    let lengths = chapters
+        .iter()
+        .map(|c| c.lines.iter().map(|l| l.content.chars().count()).sum())
+        .collect::<Vec<usize>>();
+
+    let mut sorted = lengths.clone();
+    sorted.sort_unstable();
+    let median = sorted[sorted.len() / 2];
+
+    for (chapter, &len) in chapters.iter().zip(&lengths) {
+        // empty chapters are already reported.
+        if len > 0 && (len * OUTLIER_FACTOR < median || len > median * OUTLIER_MAX_FACTOR) {
+            push(
+                Rule::OutlierLength,
+                chapter.line,
+                format!(
+                    "chapter `{}` has {} characters, the median is {}",
+                    chapter.title, len, median
+                ),
+            );
+        }
+    }
+}
+
+fn check_quotes(chapters: &[&BookChapter], push: &mut impl FnMut(Rule, usize, String)) {
+    let count = |quotes: &[char]| {
+        chapters
+            .iter()
+            .flat_map(|c| &c.lines)
+            .map(|l| l.content.chars().filter(|c| quotes.contains(c)).count())
+            .sum::<usize>()
+    };
+
+    let western = count(&['“', '”', '‘', '’']);
+    let corner = count(&['「', '」', '『', '』']);
+
+    if western > 0 && corner > 0 {
+        push(
+            Rule::MixedQuotes,
+            0,
+            format!(
+                "{} “” quotes and {} 「」 quotes are mixed, consider --replace-quote",
+                western, corner
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::{Issue, Rule, Severity};
+    use crate::{cli::OutputFormat, converter::Converter, error::Txt2EpubError};
+
+    fn lint_text(converter: Converter, text: &str) -> Result<Vec<Issue>, Box<dyn Error>> {
+        let mut converter = converter;
+        let book = converter.parse_bytes(text.as_bytes())?;
+
+        Ok(converter.lint(&book))
+    }
+
+    fn rules(issues: &[Issue]) -> Vec<(Rule, usize)> {
+        issues.iter().map(|i| (i.rule, i.line)).collect()
+    }
+
+    const BOOK: &str = "第一卷 开始
+
+第一章 相遇
+他说：“你好。”
+
+第二章 相遇
+她说：「好。」
+
+第四章 这个标题长得不像是一个标题而更像是正文里的一句话吧大概是的没错
+正文。
+
+第五章 空白
+第二卷 空卷
+第三卷 结束
+第一章 重逢
+请记住本站域名 www.example.com
+";
+
+    #[test]
+    fn rules_and_default_levels() -> Result<(), Box<dyn Error>> {
+        let issues = lint_text(Converter::new(), BOOK)?;
+
+        assert_eq!(
+            rules(&issues),
+            [
+                (Rule::MixedQuotes, 0),
+                (Rule::DuplicateTitle, 6),
+                (Rule::Numbering, 9),
+                (Rule::LongTitle, 9),
+                (Rule::EmptyChapter, 12),
+                (Rule::EmptyPart, 13),
+                (Rule::Ads, 16),
+            ]
+        );
+        assert!(issues.iter().all(|i| i.severity == Severity::Warn));
+
+        Ok(())
+    }
+
+    #[test]
+    fn levels() -> Result<(), Box<dyn Error>> {
+        let converter = Converter::new()
+            .lint_level(Rule::MixedQuotes, Severity::Allow)
+            .lint_level(Rule::Ads, Severity::Deny)
+            .lint_level(Rule::DuplicateTitle, Severity::Allow);
+        let issues = lint_text(converter, BOOK)?;
+
+        assert_eq!(
+            rules(&issues),
+            [
+                (Rule::Numbering, 9),
+                (Rule::LongTitle, 9),
+                (Rule::EmptyChapter, 12),
+                (Rule::EmptyPart, 13),
+                (Rule::Ads, 16),
+            ]
+        );
+        assert_eq!(issues[0].severity, Severity::Warn);
+        assert_eq!(issues[4].severity, Severity::Deny);
+
+        let converter = Converter::new()
+            .lint_level(Rule::Ads, Severity::Allow)
+            .deny_warnings(true);
+        let issues = lint_text(converter, BOOK)?;
+
+        assert_eq!(issues.len(), 6);
+        assert!(issues.iter().all(|i| i.severity == Severity::Deny));
+
+        Ok(())
+    }
+
+    #[test]
+    fn outlier_length() -> Result<(), Box<dyn Error>> {
+        let mut text = String::new();
+
+        for (no, content) in ["一", "二", "三", "四", "五", "六"].iter().zip([
+            "正文。".repeat(10),
+            "正文。".repeat(10),
+            "正文。".repeat(10),
+            "短。".into(),
+            "正文。".repeat(10),
+            "正文。".repeat(60),
+        ]) {
+            text.push_str(&format!("第{}章 标题{}\n{}\n", no, no, content));
+        }
+
+        let issues = lint_text(Converter::new(), &text)?;
+
+        assert_eq!(
+            rules(&issues),
+            [(Rule::OutlierLength, 7), (Rule::OutlierLength, 11)]
+        );
+        assert_eq!(
+            issues[0].message,
+            "chapter `标题四` has 2 characters, the median is 30"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn denied_issue_leaves_no_output() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("txt2epub-lint-{}.txt", std::process::id()));
+        let mut converter = Converter::new()
+            .format(OutputFormat::Txt)
+            .lint_level(Rule::Ads, Severity::Deny);
+        let book = converter.parse_bytes(BOOK.as_bytes())?;
+
+        let err = converter.write_to_path(book, &path).unwrap_err();
+
+        assert!(matches!(err, Txt2EpubError::Lint { .. }));
+        assert!(!path.exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,365 @@
+//! The language dependent strings of the output: the TOC and intro titles, the chapter and part
+//! headings and the numerals in them.
+//!
+//! The locale is chosen by `--lang`, or else by the `language` of the metadata, and defaults to
+//! simplified chinese.
+
+use chinese_number::{ChineseCase, ChineseCountMethod, ChineseVariant, NumberToChinese};
+use serde::Serialize;
+
+use crate::cli::{DEFAULT_CHAPTER_REGEX, DEFAULT_PART_REGEX};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum Locale {
+    #[default]
+    #[serde(rename = "zh-Hans")]
+    ZhHans,
+    #[serde(rename = "zh-Hant")]
+    ZhHant,
+    #[serde(rename = "ja")]
+    Ja,
+    #[serde(rename = "en")]
+    En,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Numerals {
+    /// 一百零一
+    Chinese,
+    /// 一百零一, with 萬 and 億
+    TraditionalChinese,
+    /// 百一
+    Kanji,
+    /// 101
+    Arabic,
+    /// CI
+    Roman,
+}
+
+struct Strings {
+    tag: &'static str,
+    toc: &'static str,
+    intro: &'static str,
+    chapter: &'static str,
+    part: &'static str,
+    /// the part in the label of a chapter, `卷一 · 三`.
+    part_short: &'static str,
+    /// between the number and the title of a heading.
+    separator: &'static str,
+    chapter_numerals: Numerals,
+    part_numerals: Numerals,
+    chapter_regex: &'static str,
+    part_regex: &'static str,
+}
+
+const ZH_HANS: Strings = Strings {
+    tag: "zh-CN",
+    toc: "目录",
+    intro: "简介",
+    chapter: "第{}章",
+    part: "第{}卷",
+    part_short: "卷{}",
+    separator: " ",
+    chapter_numerals: Numerals::Chinese,
+    part_numerals: Numerals::Chinese,
+    chapter_regex: DEFAULT_CHAPTER_REGEX,
+    part_regex: DEFAULT_PART_REGEX,
+};
+
+const ZH_HANT: Strings = Strings {
+    tag: "zh-TW",
+    toc: "目錄",
+    intro: "簡介",
+    chapter: "第{}章",
+    part: "第{}卷",
+    part_short: "卷{}",
+    separator: " ",
+    chapter_numerals: Numerals::TraditionalChinese,
+    part_numerals: Numerals::TraditionalChinese,
+    chapter_regex: DEFAULT_CHAPTER_REGEX,
+    part_regex: DEFAULT_PART_REGEX,
+};
+
+const JA: Strings = Strings {
+    tag: "ja",
+    toc: "目次",
+    intro: "あらすじ",
+    chapter: "第{}章",
+    part: "第{}巻",
+    part_short: "第{}巻",
+    separator: " ",
+    chapter_numerals: Numerals::Kanji,
+    part_numerals: Numerals::Kanji,
+    chapter_regex: "^第.+[章話] (.*)$",
+    part_regex: "^第.+[部巻] (.*)$",
+};
+
+const EN: Strings = Strings {
+    tag: "en",
+    toc: "Contents",
+    intro: "Introduction",
+    chapter: "Chapter {}",
+    part: "Part {}",
+    part_short: "Part {}",
+    separator: ": ",
+    chapter_numerals: Numerals::Arabic,
+    part_numerals: Numerals::Roman,
+    chapter_regex: r"^(?i:chapter)\s+(?:\d+|[IVXLCDM]+)\b[:.]?\s*(.*)$",
+    part_regex: r"^(?i:part|book|volume)\s+(?:\d+|[IVXLCDM]+)\b[:.]?\s*(.*)$",
+};
+
+impl Locale {
+    /// The locale of a BCP 47 language tag such as `zh-TW` or `en-GB`, only the language and
+    /// script or region are looked at.
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        let tag = tag.trim().to_lowercase().replace('_', "-");
+        let mut subtags = tag.split('-');
+
+        match subtags.next()? {
+            "zh" => match subtags.next() {
+                Some("hant" | "tw" | "hk" | "mo") => Some(Locale::ZhHant),
+                _ => Some(Locale::ZhHans),
+            },
+            "ja" => Some(Locale::Ja),
+            "en" => Some(Locale::En),
+            _ => None,
+        }
+    }
+
+    fn strings(&self) -> &'static Strings {
+        match self {
+            Locale::ZhHans => &ZH_HANS,
+            Locale::ZhHant => &ZH_HANT,
+            Locale::Ja => &JA,
+            Locale::En => &EN,
+        }
+    }
+
+    /// The language tag written into the output when the metadata sets none.
+    pub fn tag(&self) -> &'static str {
+        self.strings().tag
+    }
+
+    pub fn toc_name(&self) -> &'static str {
+        self.strings().toc
+    }
+
+    pub fn intro_title(&self) -> &'static str {
+        self.strings().intro
+    }
+
+    /// The numerals of the chapter numbers.
+    pub fn chapter_numerals(&self) -> Numerals {
+        self.strings().chapter_numerals
+    }
+
+    /// The numerals of the part numbers.
+    pub fn part_numerals(&self) -> Numerals {
+        self.strings().part_numerals
+    }
+
+    /// The default regex to match chapter title.
+    pub fn chapter_regex(&self) -> &'static str {
+        self.strings().chapter_regex
+    }
+
+    /// The default regex to match part title.
+    pub fn part_regex(&self) -> &'static str {
+        self.strings().part_regex
+    }
+
+    /// `第一章 title`.
+    pub fn chapter_title(&self, no: usize, title: &str) -> String {
+        let strings = self.strings();
+        heading(
+            &format_no(strings.chapter, no, strings.chapter_numerals),
+            strings.separator,
+            title,
+        )
+    }
+
+    /// `第一卷 title`.
+    pub fn part_title(&self, no: usize, title: &str) -> String {
+        let strings = self.strings();
+        heading(
+            &format_no(strings.part, no, strings.part_numerals),
+            strings.separator,
+            title,
+        )
+    }
+
+    /// The number above the chapter title, `卷一 · 三` in a part or `第三章` without.
+    pub fn chapter_label(&self, part_no: usize, no: usize) -> String {
+        let strings = self.strings();
+
+        if part_no == 0 {
+            format_no(strings.chapter, no, strings.chapter_numerals)
+        } else {
+            format!(
+                "{} · {}",
+                format_no(strings.part_short, part_no, strings.part_numerals),
+                strings.chapter_numerals.format(no)
+            )
+        }
+    }
+
+    /// The number above the part title, `卷一`.
+    pub fn part_label(&self, no: usize) -> String {
+        let strings = self.strings();
+        format_no(strings.part_short, no, strings.part_numerals)
+    }
+}
+
+fn format_no(pattern: &str, no: usize, numerals: Numerals) -> String {
+    pattern.replace("{}", &numerals.format(no))
+}
+
+fn heading(label: &str, separator: &str, title: &str) -> String {
+    if title.is_empty() {
+        label.to_string()
+    } else {
+        format!("{}{}{}", label, separator, title)
+    }
+}
+
+impl Numerals {
+    pub fn format(&self, no: usize) -> String {
+        match self {
+            Numerals::Chinese => chinese(no, ChineseVariant::Simple),
+            Numerals::TraditionalChinese => chinese(no, ChineseVariant::Traditional),
+            Numerals::Kanji => kanji(no),
+            Numerals::Arabic => no.to_string(),
+            Numerals::Roman => roman(no),
+        }
+    }
+}
+
+fn chinese(no: usize, variant: ChineseVariant) -> String {
+    (no as u128)
+        .to_chinese(variant, ChineseCase::Lower, ChineseCountMethod::TenThousand)
+        .unwrap()
+}
+
+/// Japanese numerals leave out the 一 before 十, 百 and 千, and the 零 of skipped digits.
+fn kanji(no: usize) -> String {
+    const DIGITS: [char; 10] = ['〇', '一', '二', '三', '四', '五', '六', '七', '八', '九'];
+    const UNITS: [(usize, &str); 3] = [(1000, "千"), (100, "百"), (10, "十")];
+    const GROUPS: [(usize, &str); 2] = [(1_0000_0000, "億"), (1_0000, "万")];
+
+    fn below_ten_thousand(mut no: usize, out: &mut String) {
+        for (unit, name) in UNITS {
+            let digit = no / unit;
+
+            if digit > 1 {
+                out.push(DIGITS[digit]);
+            }
+
+            if digit > 0 {
+                out.push_str(name);
+            }
+
+            no %= unit;
+        }
+
+        if no > 0 {
+            out.push(DIGITS[no]);
+        }
+    }
+
+    if no == 0 {
+        return DIGITS[0].to_string();
+    }
+
+    let mut out = String::new();
+    let mut rest = no;
+
+    for (group, name) in GROUPS {
+        if rest >= group {
+            below_ten_thousand(rest / group, &mut out);
+            out.push_str(name);
+            rest %= group;
+        }
+    }
+
+    below_ten_thousand(rest, &mut out);
+
+    out
+}
+
+/// Arabic numerals past 3999, which roman numerals can not write.
+fn roman(no: usize) -> String {
+    const NUMERALS: [(usize, &str); 13] = [
+        (1000, "M"),
+        (900, "CM"),
+        (500, "D"),
+        (400, "CD"),
+        (100, "C"),
+        (90, "XC"),
+        (50, "L"),
+        (40, "XL"),
+        (10, "X"),
+        (9, "IX"),
+        (5, "V"),
+        (4, "IV"),
+        (1, "I"),
+    ];
+
+    if no == 0 || no > 3999 {
+        return no.to_string();
+    }
+
+    let mut out = String::new();
+    let mut rest = no;
+
+    for (value, numeral) in NUMERALS {
+        while rest >= value {
+            out.push_str(numeral);
+            rest -= value;
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Locale, Numerals};
+
+    #[test]
+    fn numerals() {
+        let kanji = [1, 10, 11, 20, 101, 110, 1000, 1010, 10000, 12345]
+            .map(|no| Numerals::Kanji.format(no));
+        assert_eq!(
+            kanji,
+            [
+                "一",
+                "十",
+                "十一",
+                "二十",
+                "百一",
+                "百十",
+                "千",
+                "千十",
+                "一万",
+                "一万二千三百四十五"
+            ]
+        );
+
+        let roman = [1, 4, 9, 14, 40, 1994, 4000].map(|no| Numerals::Roman.format(no));
+        assert_eq!(roman, ["I", "IV", "IX", "XIV", "XL", "MCMXCIV", "4000"]);
+    }
+
+    #[test]
+    fn headings() {
+        assert_eq!(Locale::ZhHans.chapter_title(12, "标题"), "第十二章 标题");
+        assert_eq!(Locale::ZhHans.chapter_label(2, 3), "卷二 · 三");
+        assert_eq!(Locale::En.chapter_title(12, "Title"), "Chapter 12: Title");
+        assert_eq!(Locale::En.part_title(2, ""), "Part II");
+        assert_eq!(Locale::Ja.part_label(3), "第三巻");
+
+        assert_eq!(Locale::from_tag("zh-TW"), Some(Locale::ZhHant));
+        assert_eq!(Locale::from_tag("zh_Hans_CN"), Some(Locale::ZhHans));
+        assert_eq!(Locale::from_tag("en-GB"), Some(Locale::En));
+        assert_eq!(Locale::from_tag("ko"), None);
+    }
+}
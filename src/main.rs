@@ -1,22 +1,48 @@
 use clap::Parser;
-use rayon::prelude::*;
-use std::time::SystemTime;
+use std::{error::Error, time::SystemTime};
 
-use txt2epub::{cli::CLIOptions, error::AnyError, txt2epub};
+use txt2epub::{
+    batch,
+    cli::{CLIOptions, Command},
+};
 
-fn main() -> Result<(), AnyError> {
-    let options = CLIOptions::parse().check();
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut options = CLIOptions::parse().check();
     txt2epub::log::init();
 
+    if let Some(command) = options.command.take() {
+        let ok = match command {
+            Command::Lint(lint) => batch::lint_all(lint.into()),
+            Command::Validate(validate) => batch::validate_all(validate.into()),
+            Command::CachePrune(prune) => batch::prune_cache(&prune.cache(), prune.max_age()),
+        };
+
+        if !ok {
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
+
     let start = SystemTime::now();
+    let fail_fast = options.fail_fast;
+    let report = options.report.clone();
 
     log::info!("Covert Start.");
 
-    Into::<Vec<_>>::into(options)
-        .into_par_iter()
-        .for_each(txt2epub);
+    let reports = batch::convert_all(options.into(), fail_fast);
 
     log::info!("Covert Finish. Cost: {:?}", start.elapsed()?);
 
+    batch::print_summary(&reports);
+
+    if let Some(report) = report {
+        batch::write_report(&reports, &report)?;
+    }
+
+    if reports.iter().any(|r| r.is_failed()) {
+        std::process::exit(1);
+    }
+
     Ok(())
 }
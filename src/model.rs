@@ -0,0 +1,394 @@
+//! The public model of a parsed novel, dumped by `--format json` and `--format yaml` and handed
+//! to library callers by [`crate::converter::Converter`] between parsing and writing.
+//!
+//! The layout is versioned by [`SCHEMA_VERSION`]: fields may be added in a minor change, but
+//! renaming or removing one bumps the version.
+//!
+//! Offsets are byte offsets into the utf-8 decoded text, line numbers start from 1.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    heading::SectionKind,
+    novel_structure::{
+        chapter::{Chapter, Line, LineType},
+        novel::Novel,
+        part::Part,
+    },
+};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Book {
+    pub schema_version: u32,
+    /// path of the source file.
+    pub source: String,
+    /// the detected encoding of the source file.
+    pub encoding: String,
+    pub metadata: BookMetadata,
+    /// false if the novel has no part, the only part then has no title and `no` 0.
+    pub has_parts: bool,
+    pub parts: Vec<BookPart>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct BookMetadata {
+    pub title: String,
+    pub author: String,
+    pub cover: Option<String>,
+    pub description: Vec<String>,
+    /// the text before the first heading when it is not a metadata header.
+    #[serde(default)]
+    pub preface: Vec<String>,
+    /// BCP 47 language tag, e.g. `zh-CN`, `ja` or `en`.
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default)]
+    pub series: Option<String>,
+    #[serde(default)]
+    pub series_index: Option<u32>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub publisher: Option<String>,
+    /// `YYYY`, `YYYY-MM` or `YYYY-MM-DD`.
+    #[serde(default)]
+    pub published: Option<String>,
+    /// `YYYY-MM-DD` or `YYYY-MM-DDThh:mm:ssZ`.
+    #[serde(default)]
+    pub modified: Option<String>,
+    #[serde(default)]
+    pub isbn: Option<String>,
+    /// the identifier of the epub, derived from the title and author if not set.
+    #[serde(default)]
+    pub uuid: Option<String>,
+    #[serde(default)]
+    pub translators: Vec<String>,
+    #[serde(default)]
+    pub illustrators: Vec<String>,
+    #[serde(default)]
+    pub rights: Option<String>,
+    /// where the text comes from, usually an url.
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BookPart {
+    pub no: usize,
+    pub title: String,
+    /// the heading line as it is in the source file.
+    pub raw_title: String,
+    /// the number written in the heading, if the part regex captures it.
+    #[serde(default)]
+    pub heading_no: Option<usize>,
+    pub preface: Vec<String>,
+    /// line number of the heading, 0 if the part has no heading.
+    pub line: usize,
+    /// byte range of the part content, after the heading.
+    pub start: u64,
+    pub end: u64,
+    pub chapters: Vec<BookChapter>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BookChapter {
+    /// number of the chapter in the whole novel.
+    pub id: usize,
+    /// number of the chapter in its part.
+    pub no: usize,
+    pub part_no: usize,
+    pub title: String,
+    /// the heading line as it is in the source file.
+    pub raw_title: String,
+    /// name of the chapter pattern that matched the heading.
+    #[serde(default)]
+    pub pattern: String,
+    #[serde(default)]
+    pub kind: SectionKind,
+    /// the number written in the heading, if the chapter pattern captures it.
+    #[serde(default)]
+    pub heading_no: Option<usize>,
+    /// line number of the heading.
+    pub line: usize,
+    /// byte range of the chapter content, after the heading.
+    pub start: u64,
+    pub end: u64,
+    pub lines: Vec<BookLine>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BookLine {
+    pub kind: BookLineKind,
+    /// the trimmed line.
+    pub content: String,
+    pub line: usize,
+    pub offset: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BookLineKind {
+    Line,
+    Divider,
+}
+
+impl Book {
+    pub(crate) fn new(novel: Novel, source: String, encoding: String, has_parts: bool) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            source,
+            encoding,
+            metadata: novel.metadata.map(Into::into).unwrap_or_default(),
+            has_parts,
+            parts: novel.parts.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<Book> for Novel {
+    fn from(value: Book) -> Self {
+        let mut novel = Novel::new();
+        novel.metadata = Some(value.metadata.into());
+        novel.parts = value.parts.into_iter().map(Into::into).collect();
+        novel
+    }
+}
+
+impl From<BookPart> for Part {
+    fn from(value: BookPart) -> Self {
+        let mut part = Part::new(
+            value.no,
+            value.title,
+            value.raw_title,
+            value.start,
+            value.line,
+        );
+        part.end = value.end;
+        part.heading_no = value.heading_no;
+        part.preface = value.preface;
+        part.chapters = value.chapters.into_iter().map(Into::into).collect();
+        part.current_chapter_no = part.chapters.len() + 1;
+        part
+    }
+}
+
+impl From<BookChapter> for Chapter {
+    fn from(value: BookChapter) -> Self {
+        let mut chapter = Chapter::new(
+            value.id,
+            value.no,
+            value.part_no,
+            value.title,
+            value.raw_title,
+            value.start,
+            value.line,
+        );
+        chapter.end = value.end;
+        chapter.pattern = value.pattern;
+        chapter.kind = value.kind;
+        chapter.heading_no = value.heading_no;
+        chapter.content = value.lines.into_iter().map(Into::into).collect();
+        chapter
+    }
+}
+
+impl From<BookLine> for Line {
+    fn from(value: BookLine) -> Self {
+        Self {
+            line_type: match value.kind {
+                BookLineKind::Line => LineType::Line,
+                BookLineKind::Divider => LineType::Divider,
+            },
+            content: value.content,
+            offset: value.offset,
+            line_no: value.line,
+        }
+    }
+}
+
+impl From<Part> for BookPart {
+    fn from(value: Part) -> Self {
+        Self {
+            no: value.no,
+            title: value.title,
+            raw_title: value.raw_title.trim().to_string(),
+            heading_no: value.heading_no,
+            preface: value.preface,
+            line: value.line,
+            start: value.start,
+            end: value.end,
+            chapters: value.chapters.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<Chapter> for BookChapter {
+    fn from(value: Chapter) -> Self {
+        Self {
+            id: value.id,
+            no: value.no,
+            part_no: value.part_no,
+            title: value.title,
+            raw_title: value.raw_title.trim().to_string(),
+            pattern: value.pattern,
+            kind: value.kind,
+            heading_no: value.heading_no,
+            line: value.line,
+            start: value.start,
+            end: value.end,
+            lines: value.content.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<Line> for BookLine {
+    fn from(value: Line) -> Self {
+        Self {
+            kind: match value.line_type {
+                LineType::Line => BookLineKind::Line,
+                LineType::Divider => BookLineKind::Divider,
+            },
+            content: value.content,
+            line: value.line_no,
+            offset: value.offset,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use serde_json::Value;
+
+    use super::{Book, BookLineKind, SCHEMA_VERSION};
+    use crate::{cli::OutputFormat, converter::Converter};
+
+    const NOVEL: &str = "书名: 测试\n作者: 某人\n\n第一卷 开始\n\n第一章 相遇\n\n正文。\n";
+
+    fn keys(value: &Value) -> Vec<&str> {
+        let mut keys = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn json_schema() -> Result<(), Box<dyn Error>> {
+        let mut converter = Converter::new().format(OutputFormat::Json);
+        let book = converter.parse_bytes(NOVEL.as_bytes())?;
+        let mut out = vec![];
+        converter.write(book.clone(), &mut out)?;
+
+        let value: Value = serde_json::from_slice(&out)?;
+        assert_eq!(
+            keys(&value),
+            [
+                "encoding",
+                "has_parts",
+                "metadata",
+                "parts",
+                "schema_version",
+                "source"
+            ]
+        );
+        assert_eq!(value["schema_version"], SCHEMA_VERSION);
+        assert_eq!(
+            keys(&value["metadata"]),
+            [
+                "author",
+                "cover",
+                "description",
+                "illustrators",
+                "isbn",
+                "language",
+                "modified",
+                "preface",
+                "published",
+                "publisher",
+                "rights",
+                "series",
+                "series_index",
+                "source",
+                "tags",
+                "title",
+                "translators",
+                "uuid"
+            ]
+        );
+        assert_eq!(value["metadata"]["title"], "测试");
+        assert_eq!(value["metadata"]["author"], "某人");
+
+        let part = &value["parts"][0];
+        assert_eq!(
+            keys(part),
+            [
+                "chapters",
+                "end",
+                "heading_no",
+                "line",
+                "no",
+                "preface",
+                "raw_title",
+                "start",
+                "title"
+            ]
+        );
+        assert_eq!(part["raw_title"], "第一卷 开始");
+
+        let chapter = &part["chapters"][0];
+        assert_eq!(
+            keys(chapter),
+            [
+                "end",
+                "heading_no",
+                "id",
+                "kind",
+                "line",
+                "lines",
+                "no",
+                "part_no",
+                "pattern",
+                "raw_title",
+                "start",
+                "title"
+            ]
+        );
+        assert_eq!(chapter["kind"], "chapter");
+        assert_eq!(chapter["line"], 6);
+
+        let line = &chapter["lines"][0];
+        assert_eq!(keys(line), ["content", "kind", "line", "offset"]);
+        assert_eq!(line["kind"], "line");
+        assert_eq!(line["content"], "正文。");
+        assert_eq!(line["line"], 8);
+
+        assert_eq!(serde_json::from_slice::<Book>(&out)?, book);
+
+        Ok(())
+    }
+
+    #[test]
+    fn yaml_round_trip() -> Result<(), Box<dyn Error>> {
+        let mut converter = Converter::new().format(OutputFormat::Yaml);
+        let book = converter.parse_bytes(NOVEL.as_bytes())?;
+        let mut out = vec![];
+        converter.write(book.clone(), &mut out)?;
+
+        let parsed: Book = serde_yaml::from_slice(&out)?;
+        assert_eq!(parsed, book);
+        assert_eq!(parsed.schema_version, SCHEMA_VERSION);
+        assert_eq!(
+            parsed.parts[0].chapters[0].lines[0].kind,
+            BookLineKind::Line
+        );
+
+        Ok(())
+    }
+}
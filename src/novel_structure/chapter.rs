@@ -1,12 +1,16 @@
-use chinese_number::{ChineseCase, ChineseCountMethod, ChineseVariant, NumberToChinese};
-use epub_builder::EpubContent;
 use log::debug;
 use serde::Serialize;
-use tera::Context;
 
 use crate::{
-    cli::ConvertOpt, error::AnyError, line_quote_replace, quote_replace, EpubBuilderMut,
-    WriteToEpub, TEMPLATE_ENGINE,
+    cli::{ConvertOpt, DEFAULT_CHAPTER_PATTERN},
+    epub::EpubSink,
+    error::Result,
+    fb2::Fb2Chapter,
+    heading::{ChapterPattern, HeadingMatch, SectionKind},
+    html::HtmlContent,
+    line_quote_replace, quote_replace, render_template,
+    title_format::{self, Heading},
+    Fb2BuilderMut, HtmlBuilderMut, TxtBuilderMut, WriteToEpub, WriteToFb2, WriteToHtml, WriteToTxt,
 };
 
 #[derive(Debug)]
@@ -15,41 +19,84 @@ pub(crate) struct Chapter {
     pub part_no: usize,
     pub no: usize,
     pub title: String,
-    #[allow(unused)]
     pub raw_title: String,
     pub content: Vec<Line>,
-    #[allow(unused)]
     pub start: u64,
     pub end: u64,
+    /// line number of the chapter title.
+    pub line: usize,
+    /// name of the chapter pattern that matched the title.
+    pub pattern: String,
+    pub kind: SectionKind,
+    /// the number written in the title, if the pattern captures it.
+    pub heading_no: Option<usize>,
 }
 
 impl WriteToEpub for Chapter {
-    fn write_to_epub<'a>(
+    fn write_to_epub<'a, E: EpubSink>(
         self,
-        epub: EpubBuilderMut<'a>,
+        epub: &'a mut E,
         options: &mut ConvertOpt,
-    ) -> Result<EpubBuilderMut<'a>, AnyError> {
+    ) -> Result<&'a mut E> {
         Into::<SerChapter>::into(self).write_to_epub(epub, options)
     }
 }
 
+impl WriteToHtml for Chapter {
+    fn write_to_html<'a>(
+        self,
+        html: HtmlBuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<HtmlBuilderMut<'a>> {
+        Into::<SerChapter>::into(self).write_to_html(html, options)
+    }
+}
+
+impl WriteToFb2 for Chapter {
+    fn write_to_fb2<'a>(
+        self,
+        fb2: Fb2BuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<Fb2BuilderMut<'a>> {
+        Into::<SerChapter>::into(self).write_to_fb2(fb2, options)
+    }
+}
+
+impl WriteToTxt for Chapter {
+    fn write_to_txt<'a>(
+        self,
+        txt: TxtBuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<TxtBuilderMut<'a>> {
+        Into::<SerChapter>::into(self).write_to_txt(txt, options)
+    }
+}
+
 impl From<Chapter> for SerChapter {
     fn from(value: Chapter) -> Self {
         let Chapter {
             id,
             no,
             title,
+            raw_title,
             content,
             part_no,
+            kind,
+            heading_no,
             ..
         } = value;
 
         Self {
-            global_title: format!("第{}章 {}", id, title),
+            global_title: String::new(),
+            id,
             part_no,
             no,
             title,
+            raw_title: raw_title.trim().to_string(),
+            kind,
+            heading_no,
             content,
+            label: String::new(),
         }
     }
 }
@@ -62,6 +109,7 @@ impl Chapter {
         title: String,
         raw_title: String,
         start: u64,
+        line: usize,
     ) -> Self {
         Self {
             id,
@@ -72,8 +120,20 @@ impl Chapter {
             content: vec![],
             start,
             end: 0,
+            line,
+            pattern: DEFAULT_CHAPTER_PATTERN.to_string(),
+            kind: SectionKind::Chapter,
+            heading_no: None,
         }
     }
+
+    /// Record the pattern that matched the title and what it captured besides the title.
+    pub fn matched_by(mut self, pattern: &ChapterPattern, heading: &HeadingMatch) -> Self {
+        self.pattern = pattern.name.clone();
+        self.kind = heading.kind.unwrap_or(pattern.kind);
+        self.heading_no = heading.no;
+        self
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -86,76 +146,211 @@ pub(crate) enum LineType {
 pub(crate) struct Line {
     pub(crate) line_type: LineType,
     pub(crate) content: String,
+    /// byte offset of the line in the decoded utf-8 text.
+    pub(crate) offset: u64,
+    pub(crate) line_no: usize,
 }
 
 #[derive(Serialize)]
 pub(crate) struct SerChapter {
+    /// the title numbered through the whole novel, set when rendering.
     pub global_title: String,
+    pub id: usize,
     pub no: usize,
     pub part_no: usize,
     pub title: String,
+    pub raw_title: String,
+    pub kind: SectionKind,
+    pub heading_no: Option<usize>,
     pub content: Vec<Line>,
+    /// the number above the title, set when rendering.
+    pub label: String,
 }
 
 impl WriteToEpub for SerChapter {
-    fn write_to_epub<'a>(
+    fn write_to_epub<'a, E: EpubSink>(
         self,
-        epub: EpubBuilderMut<'a>,
+        epub: &'a mut E,
         options: &mut ConvertOpt,
-    ) -> Result<EpubBuilderMut<'a>, AnyError> {
-        let title = self.title_string();
-
-        debug!("writing chapter: {}", &title);
-
-        if options.have_section {
-            epub.add_content(
-                EpubContent::new(
-                    format!("{:02}/{:04}.xhtml", self.part_no, self.no),
-                    self.into_html_string(options)?.as_bytes(),
-                )
-                .title(title)
-                .level(2),
-            )?;
-        } else {
-            epub.add_content(
-                EpubContent::new(
-                    format!("00/{:04}.xhtml", self.no),
-                    self.into_html_string(options)?.as_bytes(),
-                )
-                .title(title),
-            )?;
-        }
+    ) -> Result<&'a mut E> {
+        self.epub_page(options)?.write_to_epub(epub, options)
+    }
+}
+
+impl WriteToHtml for SerChapter {
+    fn write_to_html<'a>(
+        self,
+        html: HtmlBuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<HtmlBuilderMut<'a>> {
+        html.add_content(self.html_content(options)?);
+
+        Ok(html)
+    }
+}
+
+impl WriteToFb2 for SerChapter {
+    fn write_to_fb2<'a>(
+        self,
+        fb2: Fb2BuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<Fb2BuilderMut<'a>> {
+        fb2.add_chapter(self.fb2_chapter(options));
+
+        Ok(fb2)
+    }
+}
+
+impl WriteToTxt for SerChapter {
+    fn write_to_txt<'a>(
+        self,
+        txt: TxtBuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<TxtBuilderMut<'a>> {
+        let (title, content) = self.txt_chapter(options);
+
+        txt.add_heading(&title)
+            .add_lines(content.iter().map(|l| &l.content));
+
+        Ok(txt)
+    }
+}
+
+/// A chapter page rendered for the epub, only adding it to the book is left.
+pub(crate) struct EpubPage {
+    href: String,
+    title: String,
+    level: i32,
+    html: String,
+}
+
+impl WriteToEpub for EpubPage {
+    fn write_to_epub<'a, E: EpubSink>(
+        self,
+        epub: &'a mut E,
+        _options: &mut ConvertOpt,
+    ) -> Result<&'a mut E> {
+        epub.add_page(&self.href, &self.title, self.level, &self.html)?;
 
         Ok(epub)
     }
 }
 
 impl SerChapter {
-    pub fn into_html_string(mut self, opt: &ConvertOpt) -> Result<String, AnyError> {
+    pub fn into_html_string(self, opt: &ConvertOpt) -> Result<String> {
+        self.render(opt, "chapter")
+    }
+
+    // The methods below do the costly part of writing, the transforms and the rendering, without
+    // touching the builder, so the chapters of a part can be prepared in parallel.
+
+    pub(crate) fn epub_page(self, opt: &ConvertOpt) -> Result<EpubPage> {
+        let title = self.toc_title(opt);
+
+        debug!("writing chapter: {}", title);
+
+        let (href, level) = if opt.have_section {
+            (format!("{:02}/{:04}.xhtml", self.part_no, self.no), 2)
+        } else {
+            (format!("00/{:04}.xhtml", self.no), 1)
+        };
+
+        Ok(EpubPage {
+            href,
+            title,
+            level,
+            html: self.into_html_string(opt)?,
+        })
+    }
+
+    pub(crate) fn html_content(self, opt: &ConvertOpt) -> Result<HtmlContent> {
+        let title = self.toc_title(opt);
+        let id = format!("chapter-{:02}-{:04}", self.part_no, self.no);
+        let level = if opt.have_section { 2 } else { 1 };
+
+        Ok(HtmlContent::new(id, self.render(opt, "chapter_body")?)
+            .title(title)
+            .level(level))
+    }
+
+    pub(crate) fn fb2_chapter(mut self, opt: &ConvertOpt) -> Fb2Chapter {
+        let title = self.title_string(opt);
+
+        self.transform(opt);
+
+        Fb2Chapter {
+            title,
+            content: self.content,
+        }
+    }
+
+    /// The heading and the lines of the normalised text.
+    pub(crate) fn txt_chapter(mut self, opt: &ConvertOpt) -> (String, Vec<Line>) {
+        self.transform(opt);
+
+        (self.title_string(opt), self.content)
+    }
+
+    /// Render with the given template, `chapter` for a whole page or `chapter_body` for the fragment.
+    ///
+    /// With a render cache in the options, an unchanged chapter is read back instead.
+    pub fn render(mut self, opt: &ConvertOpt, template: &str) -> Result<String> {
+        self.label = title_format::chapter_header(opt, &self.heading());
+        self.global_title = title_format::global_title(opt, &self.heading());
+
+        let Some(cache) = &opt.cache else {
+            self.transform(opt);
+            return render_template(template, self, opt);
+        };
+
+        let key = cache.key(template, &self, opt);
+
+        if let Some(html) = cache.get(&key) {
+            debug!("cache hit: {}", self.title);
+            return Ok(html);
+        }
+
+        self.transform(opt);
+        let html = render_template(template, self, opt)?;
+        cache.put(&key, &html);
+
+        Ok(html)
+    }
+
+    /// Apply the text transforms shared by every output format.
+    pub fn transform(&mut self, opt: &ConvertOpt) {
         if opt.replace_quote {
             self.content.iter_mut().for_each(line_quote_replace);
             quote_replace(&mut self.title);
+            quote_replace(&mut self.raw_title);
         }
 
         self.content
             .iter_mut()
             .for_each(|s| s.content = autocorrect::format(&s.content));
+    }
 
-        Ok(TEMPLATE_ENGINE.render("chapter", &Context::from_serialize(self)?)?)
+    pub fn title_string(&self, opt: &ConvertOpt) -> String {
+        title_format::chapter_title(opt, &self.heading())
     }
 
-    pub fn title_string(&self) -> String {
-        format!(
-            "第{}章 {}",
-            (self.no as u128)
-                .to_chinese(
-                    ChineseVariant::Simple,
-                    ChineseCase::Lower,
-                    ChineseCountMethod::TenThousand,
-                )
-                .unwrap(),
-            self.title
-        )
+    /// The title in the TOC, numbered through the whole novel with a `global` title format.
+    fn toc_title(&self, opt: &ConvertOpt) -> String {
+        match opt.title_formats.global {
+            Some(_) => title_format::global_title(opt, &self.heading()),
+            None => self.title_string(opt),
+        }
+    }
+
+    fn heading(&self) -> Heading<'_> {
+        Heading {
+            no: self.heading_no.unwrap_or(self.no),
+            part_no: self.part_no,
+            id: self.id,
+            title: &self.title,
+            raw_title: &self.raw_title,
+            kind: self.kind,
+        }
     }
 }
 
@@ -165,7 +360,11 @@ mod tests {
 
     use regex::Regex;
 
-    use crate::cli::ConvertOpt;
+    use crate::{
+        cli::ConvertOpt,
+        heading::{ChapterPattern, SectionKind},
+        title_format::{TitleFormats, TitleKind},
+    };
 
     use super::{Line, LineType, SerChapter};
 
@@ -175,23 +374,34 @@ mod tests {
 
         let chapter = SerChapter {
             global_title: "第1章".into(),
+            id: 1,
             no: 1,
             part_no: 1,
             title: "测试".into(),
+            raw_title: "第一章 测试".into(),
+            kind: SectionKind::Chapter,
+            heading_no: None,
             content: vec![
                 Line {
                     line_type: LineType::Line,
                     content: "测试".into(),
+                    offset: 0,
+                    line_no: 0,
                 },
                 Line {
                     line_type: LineType::Divider,
                     content: "---".into(),
+                    offset: 0,
+                    line_no: 0,
                 },
                 Line {
                     line_type: LineType::Line,
                     content: "测试".into(),
+                    offset: 0,
+                    line_no: 0,
                 },
             ],
+            label: String::new(),
         };
 
         let res = chapter.into_html_string(&ConvertOpt {
@@ -200,13 +410,76 @@ mod tests {
             out_file: "".into(),
             have_section: false,
             part_regex: Regex::new("1").unwrap(),
-            chapter_regex: Regex::new("1").unwrap(),
+            chapter_patterns: vec![ChapterPattern::new("1", Regex::new("1").unwrap())],
             replace_quote: false,
             long_preface: false,
             divider: vec![],
+            ..Default::default()
         })?;
 
-        res.lines().for_each(|l| println!("{}", l));
+        let doc = roxmltree::Document::parse(&res)?;
+        let text = |tag: &str| {
+            doc.descendants()
+                .find(|n| n.has_tag_name(tag))
+                .and_then(|n| n.text())
+                .map(str::trim)
+        };
+
+        assert_eq!(text("title"), Some("第一章 测试"));
+        assert_eq!(text("h3"), Some("卷一 · 一"));
+        assert_eq!(text("h2"), Some("测试"));
+
+        let paragraphs = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("p"))
+            .map(|p| (p.attribute("class").unwrap(), p.text().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paragraphs,
+            [
+                ("content", "测试"),
+                ("content-divider", "---"),
+                ("content", "测试")
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn global_title() -> Result<(), Box<dyn Error>> {
+        let chapter = || SerChapter {
+            global_title: String::new(),
+            id: 40,
+            no: 2,
+            part_no: 3,
+            title: "测试".into(),
+            raw_title: "第二章 测试".into(),
+            kind: SectionKind::Chapter,
+            heading_no: None,
+            content: vec![],
+            label: String::new(),
+        };
+        let page_title = |html: &str| -> Result<String, Box<dyn Error>> {
+            let doc = roxmltree::Document::parse(html)?;
+            let title = doc.descendants().find(|n| n.has_tag_name("title"));
+
+            Ok(title.and_then(|n| n.text()).unwrap_or_default().to_string())
+        };
+
+        let mut opt = ConvertOpt::default();
+        let page = chapter().epub_page(&opt)?;
+        assert_eq!(page.title, "第二章 测试");
+        assert_eq!(page_title(&page.html)?, "第四十章 测试");
+
+        let mut formats = TitleFormats::default();
+        formats.set(TitleKind::Global, "{id:arabic}. {title}".parse()?);
+        opt.title_formats = formats;
+
+        let page = chapter().epub_page(&opt)?;
+        assert_eq!(page.title, "40. 测试");
+        assert_eq!(page_title(&page.html)?, "40. 测试");
+        assert_eq!(chapter().html_content(&opt)?.title, "40. 测试");
 
         Ok(())
     }
@@ -1,51 +1,166 @@
-use colored::Colorize;
-use epub_builder::EpubContent;
-use log::warn;
-use serde::{Deserialize, Serialize};
-use tera::Context;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::{
-    cli::ConvertOpt, error::AnyError, get_cover_image, quote_replace, EpubBuilderMut, WriteToEpub,
-    TEMPLATE_ENGINE,
+    cli::ConvertOpt,
+    epub::{EpubSink, PackageMetadata},
+    error::Result,
+    get_cover_image,
+    html::HtmlContent,
+    model::BookMetadata,
+    quote_replace, render_template, Fb2BuilderMut, HtmlBuilderMut, TxtBuilderMut, WriteToEpub,
+    WriteToFb2, WriteToHtml, WriteToTxt,
 };
 
+lazy_static! {
+    static ref PUBLISHED: Regex = Regex::new(r"^\d{4}(-\d{2}(-\d{2})?)?$").unwrap();
+    static ref DATE: Regex = Regex::new(r"^\d{4}-\d{2}-\d{2}$").unwrap();
+    static ref TIMESTAMP: Regex = Regex::new(r"^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}Z$").unwrap();
+    static ref ISBN: Regex = Regex::new(r"^(\d{9}[\dXx]|\d{13})$").unwrap();
+    static ref UUID: Regex =
+        Regex::new(r"^[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}$").unwrap();
+}
+
 pub mod chapter;
 pub mod novel;
 pub mod part;
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Serialize, Debug, Default)]
 pub struct Metadata {
-    #[serde(alias = "书名")]
+    #[serde(alias = "书名", rename(serialize = "书名"))]
     #[serde(default)]
     book_name: String,
-    #[serde(alias = "作者")]
+    #[serde(alias = "作者", rename(serialize = "作者"))]
     #[serde(default)]
     author: String,
-    #[serde(alias = "封面")]
-    #[serde(default)]
+    #[serde(alias = "封面", rename(serialize = "封面"))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     cover: Option<String>,
-    #[serde(alias = "简介")]
+    #[serde(alias = "简介", rename(serialize = "简介"))]
     #[serde(default)]
     description: Vec<String>,
+    /// the text before the first heading when it is not a metadata header.
+    #[serde(alias = "前言", rename(serialize = "前言"))]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    preface: Vec<String>,
+    /// BCP 47 language tag, e.g. `zh-CN`, `ja` or `en`.
+    #[serde(alias = "语言", rename(serialize = "语言"))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
+    #[serde(alias = "系列", rename(serialize = "系列"))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    series: Option<String>,
+    /// position of the book in the series, from 1.
+    #[serde(alias = "系列序号", rename(serialize = "系列序号"))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    series_index: Option<u32>,
+    #[serde(alias = "标签", rename(serialize = "标签"))]
+    #[serde(default, deserialize_with = "one_or_many")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(alias = "出版社", rename(serialize = "出版社"))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    publisher: Option<String>,
+    /// `YYYY`, `YYYY-MM` or `YYYY-MM-DD`.
+    #[serde(alias = "出版日期", rename(serialize = "出版日期"))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    published: Option<String>,
+    /// `YYYY-MM-DD` or `YYYY-MM-DDThh:mm:ssZ`.
+    #[serde(alias = "修改日期", rename(serialize = "修改日期"))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    modified: Option<String>,
+    #[serde(alias = "ISBN", rename(serialize = "ISBN"))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    isbn: Option<String>,
+    /// the identifier of the epub, derived from the title and author if not set.
+    #[serde(alias = "UUID", rename(serialize = "UUID"))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uuid: Option<String>,
+    #[serde(alias = "译者", rename(serialize = "译者"))]
+    #[serde(default, deserialize_with = "one_or_many")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    translators: Vec<String>,
+    #[serde(alias = "插画", alias = "插画师", rename(serialize = "插画"))]
+    #[serde(default, deserialize_with = "one_or_many")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    illustrators: Vec<String>,
+    #[serde(alias = "版权", rename(serialize = "版权"))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rights: Option<String>,
+    /// where the text comes from, usually an url.
+    #[serde(alias = "来源", rename(serialize = "来源"))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+}
+
+/// The keys of the metadata header and their chinese aliases.
+pub(crate) const METADATA_KEYS: &[&str] = &[
+    "book_name",
+    "书名",
+    "author",
+    "作者",
+    "cover",
+    "封面",
+    "description",
+    "简介",
+    "preface",
+    "前言",
+    "language",
+    "语言",
+    "series",
+    "系列",
+    "series_index",
+    "系列序号",
+    "tags",
+    "标签",
+    "publisher",
+    "出版社",
+    "published",
+    "出版日期",
+    "modified",
+    "修改日期",
+    "isbn",
+    "ISBN",
+    "uuid",
+    "UUID",
+    "translators",
+    "译者",
+    "illustrators",
+    "插画",
+    "插画师",
+    "rights",
+    "版权",
+    "source",
+    "来源",
+];
+
+/// Accept `译者: 张三` as well as a list.
+fn one_or_many<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
 }
 
 impl WriteToEpub for Metadata {
-    fn write_to_epub<'a>(
+    fn write_to_epub<'a, E: EpubSink>(
         self,
-        epub: EpubBuilderMut<'a>,
+        epub: &'a mut E,
         options: &mut ConvertOpt,
-    ) -> Result<EpubBuilderMut<'a>, AnyError> {
-        if self.cover.is_some() {
-            match get_cover_image(self.cover.as_ref().unwrap()) {
-                Ok(cover) => {
-                    epub.add_cover_image("cover.jpg", &cover[..], "image/jpeg")?;
-                }
-                Err(e) => {
-                    warn!("Failed to add cover image. Due to: ");
-                    warn!("{}", e.to_string().on_yellow());
-                    warn!("Skip adding cover image.");
-                }
-            }
+    ) -> Result<&'a mut E> {
+        if let Some(cover) = self.fetch_cover(options) {
+            epub.add_cover_image("cover.jpg", &cover)?;
         }
 
         Into::<SerMetaData>::into(self).write_to_epub(epub, options)?;
@@ -54,12 +169,280 @@ impl WriteToEpub for Metadata {
     }
 }
 
+impl WriteToFb2 for Metadata {
+    fn write_to_fb2<'a>(
+        self,
+        fb2: Fb2BuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<Fb2BuilderMut<'a>> {
+        if let Some(cover) = self.fetch_cover(options) {
+            fb2.cover(cover);
+        }
+
+        Into::<SerMetaData>::into(self).write_to_fb2(fb2, options)
+    }
+}
+
+impl WriteToTxt for Metadata {
+    fn write_to_txt<'a>(
+        mut self,
+        txt: TxtBuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<TxtBuilderMut<'a>> {
+        if options.replace_quote {
+            self.description.iter_mut().for_each(quote_replace);
+            self.preface.iter_mut().for_each(quote_replace);
+        }
+
+        txt.metadata(&serde_yaml::to_string(&self).map_err(std::io::Error::other)?);
+
+        Ok(txt)
+    }
+}
+
+impl Metadata {
+    /// Use `name` as the title if no source sets one.
+    pub(crate) fn default_title(&mut self, name: &str) {
+        if self.book_name.is_empty() {
+            self.book_name = name.to_string();
+        }
+    }
+
+    /// Take every field set in `other`.
+    pub(crate) fn merge(&mut self, other: Metadata) {
+        fn take<T>(field: &mut Option<T>, other: Option<T>) {
+            if other.is_some() {
+                *field = other;
+            }
+        }
+
+        fn take_all<T>(field: &mut Vec<T>, other: Vec<T>) {
+            if !other.is_empty() {
+                *field = other;
+            }
+        }
+
+        if !other.book_name.is_empty() {
+            self.book_name = other.book_name;
+        }
+
+        if !other.author.is_empty() {
+            self.author = other.author;
+        }
+
+        take(&mut self.cover, other.cover);
+        take_all(&mut self.description, other.description);
+        take_all(&mut self.preface, other.preface);
+        take(&mut self.language, other.language);
+        take(&mut self.series, other.series);
+        take(&mut self.series_index, other.series_index);
+        take_all(&mut self.tags, other.tags);
+        take(&mut self.publisher, other.publisher);
+        take(&mut self.published, other.published);
+        take(&mut self.modified, other.modified);
+        take(&mut self.isbn, other.isbn);
+        take(&mut self.uuid, other.uuid);
+        take_all(&mut self.translators, other.translators);
+        take_all(&mut self.illustrators, other.illustrators);
+        take(&mut self.rights, other.rights);
+        take(&mut self.source, other.source);
+    }
+
+    /// Check the identifiers and dates, and strip the separators of the identifiers.
+    ///
+    /// On error, returns the offending value with the reason.
+    pub(crate) fn normalize(&mut self) -> std::result::Result<(), (String, String)> {
+        if let Some(published) = &self.published {
+            if !PUBLISHED.is_match(published) {
+                return Err((
+                    published.clone(),
+                    "the publication date must be YYYY, YYYY-MM or YYYY-MM-DD".into(),
+                ));
+            }
+        }
+
+        if let Some(modified) = &self.modified {
+            if !DATE.is_match(modified) && !TIMESTAMP.is_match(modified) {
+                return Err((
+                    modified.clone(),
+                    "the modification date must be YYYY-MM-DD or YYYY-MM-DDThh:mm:ssZ".into(),
+                ));
+            }
+        }
+
+        if let Some(isbn) = &mut self.isbn {
+            let digits = isbn
+                .chars()
+                .filter(|c| !matches!(c, '-' | ' '))
+                .collect::<String>();
+
+            if !ISBN.is_match(&digits) {
+                return Err((isbn.clone(), "not an ISBN-10 or ISBN-13".into()));
+            }
+
+            *isbn = digits;
+        }
+
+        if let Some(uuid) = &mut self.uuid {
+            let normalized = uuid.to_lowercase();
+            let normalized = normalized.trim_start_matches("urn:uuid:");
+
+            if !UUID.is_match(normalized) {
+                return Err((uuid.clone(), "not a UUID".into()));
+            }
+
+            *uuid = normalized.to_string();
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn language(&self) -> Option<&str> {
+        self.language.as_deref()
+    }
+
+    /// The metadata epub-builder can not write, see [`PackageMetadata`].
+    pub(crate) fn package_metadata(&self) -> PackageMetadata {
+        PackageMetadata {
+            title: self.book_name.clone(),
+            author: self.author.clone(),
+            series: self.series.clone(),
+            series_index: self.series_index,
+            tags: self.tags.clone(),
+            publisher: self.publisher.clone(),
+            published: self.published.clone(),
+            modified: self.modified.clone(),
+            isbn: self.isbn.clone(),
+            uuid: self.uuid.clone(),
+            translators: self.translators.clone(),
+            illustrators: self.illustrators.clone(),
+            rights: self.rights.clone(),
+            source: self.source.clone(),
+        }
+    }
+
+    /// Fetch the cover image as jpeg, a failure only skips the cover.
+    fn fetch_cover(&self, options: &mut ConvertOpt) -> Option<Vec<u8>> {
+        match get_cover_image(self.cover.as_ref()?) {
+            Ok(cover) => Some(cover),
+            Err(e) => {
+                options.warn(format!("skipped the cover image: {}", e));
+                None
+            }
+        }
+    }
+}
+
+impl WriteToHtml for Metadata {
+    fn write_to_html<'a>(
+        self,
+        html: HtmlBuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<HtmlBuilderMut<'a>> {
+        Into::<SerMetaData>::into(self).write_to_html(html, options)
+    }
+}
+
+impl From<Metadata> for BookMetadata {
+    fn from(value: Metadata) -> Self {
+        let Metadata {
+            book_name,
+            author,
+            cover,
+            description,
+            preface,
+            language,
+            series,
+            series_index,
+            tags,
+            publisher,
+            published,
+            modified,
+            isbn,
+            uuid,
+            translators,
+            illustrators,
+            rights,
+            source,
+        } = value;
+
+        Self {
+            title: book_name,
+            author,
+            cover,
+            description,
+            preface,
+            language,
+            series,
+            series_index,
+            tags,
+            publisher,
+            published,
+            modified,
+            isbn,
+            uuid,
+            translators,
+            illustrators,
+            rights,
+            source,
+        }
+    }
+}
+
+impl From<BookMetadata> for Metadata {
+    fn from(value: BookMetadata) -> Self {
+        let BookMetadata {
+            title,
+            author,
+            cover,
+            description,
+            preface,
+            language,
+            series,
+            series_index,
+            tags,
+            publisher,
+            published,
+            modified,
+            isbn,
+            uuid,
+            translators,
+            illustrators,
+            rights,
+            source,
+        } = value;
+
+        Self {
+            book_name: title,
+            author,
+            cover,
+            description,
+            preface,
+            language,
+            series,
+            series_index,
+            tags,
+            publisher,
+            published,
+            modified,
+            isbn,
+            uuid,
+            translators,
+            illustrators,
+            rights,
+            source,
+        }
+    }
+}
+
 impl From<Metadata> for SerMetaData {
     fn from(value: Metadata) -> Self {
         let Metadata {
             book_name,
             author,
             description,
+            preface,
+            language,
             ..
         } = value;
 
@@ -67,6 +450,8 @@ impl From<Metadata> for SerMetaData {
             book_name,
             author,
             description,
+            preface,
+            language,
         }
     }
 }
@@ -77,37 +462,151 @@ pub struct SerMetaData {
     book_name: String,
     author: String,
     description: Vec<String>,
+    preface: Vec<String>,
+    /// the language tag of the output, the one of the locale if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
 }
 
 impl SerMetaData {
-    pub fn into_html_string(mut self, opt: &ConvertOpt) -> anyhow::Result<String> {
+    pub fn into_html_string(self, opt: &ConvertOpt) -> Result<String> {
+        self.render(opt, "intro")
+    }
+
+    /// Render with the given template, `intro` for a whole page or `intro_body` for the fragment.
+    pub fn render(mut self, opt: &ConvertOpt, template: &str) -> Result<String> {
+        self.transform(opt);
+        render_template(template, self, opt)
+    }
+
+    /// Apply the text transforms shared by every output format.
+    pub fn transform(&mut self, opt: &ConvertOpt) {
         if opt.replace_quote {
             self.description.iter_mut().for_each(quote_replace);
+            self.preface.iter_mut().for_each(quote_replace);
         }
-        Ok(TEMPLATE_ENGINE.render("intro", &Context::from_serialize(self)?)?)
+    }
+
+    fn lang(&self, opt: &ConvertOpt) -> String {
+        self.language
+            .clone()
+            .unwrap_or_else(|| opt.locale.tag().to_string())
     }
 }
 
 impl WriteToEpub for SerMetaData {
-    fn write_to_epub<'a>(
+    fn write_to_epub<'a, E: EpubSink>(
         mut self,
-        epub: EpubBuilderMut<'a>,
+        epub: &'a mut E,
         opt: &mut ConvertOpt,
-    ) -> Result<EpubBuilderMut<'a>, AnyError> {
-        if opt.replace_quote {
-            self.description.iter_mut().for_each(quote_replace);
-        }
+    ) -> Result<&'a mut E> {
+        self.transform(opt);
 
-        epub.metadata("author", &self.author)?
-            .metadata("title", &self.book_name)?
-            .metadata("lang", "zh-CN")?
-            .metadata("toc_name", "目录")?
-            .metadata("description", self.description.join("\n"))?;
+        for (key, value) in [
+            ("author", self.author.clone()),
+            ("title", self.book_name.clone()),
+            ("lang", self.lang(opt)),
+            ("toc_name", opt.locale.toc_name().into()),
+            ("description", self.description.join("\n")),
+        ] {
+            epub.metadata(key, value)?;
+        }
 
-        epub.add_content(
-            EpubContent::new("intro.html", self.into_html_string(opt)?.as_bytes()).title("简介"),
+        // transformed above, so rendered as it is.
+        let title = opt.locale.intro_title();
+        epub.add_page(
+            "intro.html",
+            title,
+            1,
+            &render_template("intro", self, opt)?,
         )?;
 
         Ok(epub)
     }
 }
+
+impl WriteToHtml for SerMetaData {
+    fn write_to_html<'a>(
+        self,
+        html: HtmlBuilderMut<'a>,
+        opt: &mut ConvertOpt,
+    ) -> Result<HtmlBuilderMut<'a>> {
+        html.metadata(&self.book_name, &self.author)
+            .language(&self.lang(opt), opt.locale.toc_name());
+        html.add_content(
+            HtmlContent::new("intro", self.render(opt, "intro_body")?)
+                .title(opt.locale.intro_title()),
+        );
+
+        Ok(html)
+    }
+}
+
+impl WriteToFb2 for SerMetaData {
+    fn write_to_fb2<'a>(
+        mut self,
+        fb2: Fb2BuilderMut<'a>,
+        opt: &mut ConvertOpt,
+    ) -> Result<Fb2BuilderMut<'a>> {
+        self.transform(opt);
+        let lang = self.lang(opt);
+        let mut annotation = self.description;
+        annotation.extend(self.preface);
+
+        fb2.metadata(&self.book_name, &self.author, annotation)
+            .language(&lang);
+
+        Ok(fb2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metadata, METADATA_KEYS};
+    use crate::model::BookMetadata;
+
+    #[test]
+    fn metadata_keys_match_the_fields() {
+        let default = serde_yaml::to_string(&Metadata::default()).unwrap();
+
+        // every key sets a field, with one of the value shapes the fields take.
+        for key in METADATA_KEYS {
+            let set = ["x", "[x]", "1"].iter().any(|value| {
+                serde_yaml::from_str::<Metadata>(&format!("{}: {}", key, value))
+                    .is_ok_and(|m| serde_yaml::to_string(&m).unwrap() != default)
+            });
+
+            assert!(set, "`{}` sets no field", key);
+        }
+
+        // every field written back has its key.
+        let full = Metadata::from(BookMetadata {
+            title: "x".into(),
+            author: "x".into(),
+            cover: Some("x".into()),
+            description: vec!["x".into()],
+            preface: vec!["x".into()],
+            language: Some("x".into()),
+            series: Some("x".into()),
+            series_index: Some(1),
+            tags: vec!["x".into()],
+            publisher: Some("x".into()),
+            published: Some("x".into()),
+            modified: Some("x".into()),
+            isbn: Some("x".into()),
+            uuid: Some("x".into()),
+            translators: vec!["x".into()],
+            illustrators: vec!["x".into()],
+            rights: Some("x".into()),
+            source: Some("x".into()),
+        });
+        let written = serde_yaml::to_value(full).unwrap();
+        let written = written.as_mapping().unwrap();
+
+        assert_eq!(written.len(), 18);
+
+        for key in written.keys() {
+            assert!(METADATA_KEYS.contains(&key.as_str().unwrap()), "{:?}", key);
+        }
+    }
+}
@@ -1,10 +1,23 @@
-use std::io::{BufRead, Seek};
+use std::{
+    fs,
+    io::{self, BufRead, Seek},
+    path::Path,
+};
 
-use anyhow::Result;
 use log::{debug, info};
-
-use super::{part::Part, Metadata};
-use crate::{cli::ConvertOpt, error::AnyError, EpubBuilderMut, WriteToEpub};
+use serde_yaml::{Mapping, Value};
+
+use super::{
+    part::{HeadingScan, Part},
+    Metadata, METADATA_KEYS,
+};
+use crate::{
+    cli::ConvertOpt,
+    epub::{EpubSink, PackageMetadata},
+    error::{Result, Txt2EpubError},
+    heading::{describe, match_chapter, match_heading},
+    Fb2BuilderMut, HtmlBuilderMut, TxtBuilderMut, WriteToEpub, WriteToFb2, WriteToHtml, WriteToTxt,
+};
 
 #[derive(Debug, Default)]
 pub struct Novel {
@@ -14,11 +27,11 @@ pub struct Novel {
 }
 
 impl WriteToEpub for Novel {
-    fn write_to_epub<'a>(
+    fn write_to_epub<'a, E: EpubSink>(
         self,
-        epub: EpubBuilderMut<'a>,
+        epub: &'a mut E,
         options: &mut ConvertOpt,
-    ) -> Result<EpubBuilderMut<'a>, AnyError> {
+    ) -> Result<&'a mut E> {
         debug!("writing metadata.");
 
         if let Some(metadata) = self.metadata {
@@ -33,6 +46,60 @@ impl WriteToEpub for Novel {
     }
 }
 
+impl WriteToHtml for Novel {
+    fn write_to_html<'a>(
+        self,
+        html: HtmlBuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<HtmlBuilderMut<'a>> {
+        if let Some(metadata) = self.metadata {
+            metadata.write_to_html(html, options)?;
+        }
+
+        for part in self.parts {
+            part.write_to_html(html, options).map(|_| ())?;
+        }
+
+        Ok(html)
+    }
+}
+
+impl WriteToFb2 for Novel {
+    fn write_to_fb2<'a>(
+        self,
+        fb2: Fb2BuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<Fb2BuilderMut<'a>> {
+        if let Some(metadata) = self.metadata {
+            metadata.write_to_fb2(fb2, options)?;
+        }
+
+        for part in self.parts {
+            part.write_to_fb2(fb2, options).map(|_| ())?;
+        }
+
+        Ok(fb2)
+    }
+}
+
+impl WriteToTxt for Novel {
+    fn write_to_txt<'a>(
+        self,
+        txt: TxtBuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<TxtBuilderMut<'a>> {
+        if let Some(metadata) = self.metadata {
+            metadata.write_to_txt(txt, options)?;
+        }
+
+        for part in self.parts {
+            part.write_to_txt(txt, options).map(|_| ())?;
+        }
+
+        Ok(txt)
+    }
+}
+
 impl Novel {
     pub fn new() -> Self {
         Self {
@@ -42,6 +109,13 @@ impl Novel {
         }
     }
 
+    pub(crate) fn package_metadata(&self) -> PackageMetadata {
+        self.metadata
+            .as_ref()
+            .map(Metadata::package_metadata)
+            .unwrap_or_default()
+    }
+
     pub(crate) fn scan_metadata<F>(&mut self, file: &mut F, options: &ConvertOpt) -> Result<()>
     where
         F: BufRead + Seek,
@@ -51,21 +125,38 @@ impl Novel {
         debug!("scanning novel metadata.");
 
         let mut line = String::new();
+        let mut line_no = 0;
         let mut metadata_string = String::new();
-        let part_regex = &options.part_regex;
-        let chapter_regex = &options.chapter_regex;
+        let mut scan = HeadingScan::new(&options.guards, u64::MAX);
 
         while let Ok(len) = file.read_line(&mut line) {
-            if len == 0 || part_regex.is_match(line.trim()) || chapter_regex.is_match(line.trim()) {
+            if len == 0 {
                 break;
             }
 
+            line_no += 1;
+
+            let trimed_line = line.trim();
+
+            if let Some(heading) = match_heading(&options.part_regex, trimed_line) {
+                if scan.accept(file, line_no, trimed_line, &heading, "part")? {
+                    break;
+                }
+            }
+
+            if let Some((_, heading)) = match_chapter(&options.chapter_patterns, trimed_line) {
+                if scan.accept(file, line_no, trimed_line, &heading, "chapter")? {
+                    break;
+                }
+            }
+
+            scan.advance(trimed_line);
             metadata_string += &line;
 
             line.clear();
         }
 
-        self.metadata = Some(serde_yaml::from_str(&metadata_string)?);
+        self.metadata = Some(parse_metadata(&metadata_string, options)?);
 
         debug!("{:#?}", self.metadata);
 
@@ -113,6 +204,8 @@ impl Novel {
         file.rewind()?;
 
         let mut line = String::new();
+        let mut line_no = 0;
+        let mut scan = HeadingScan::new(&options.guards, u64::MAX);
 
         let part_regex = &options.part_regex;
 
@@ -121,17 +214,35 @@ impl Novel {
                 break;
             }
 
-            if let Some(cap) = part_regex.captures(line.trim()) {
+            line_no += 1;
+
+            let trimed_line = line.trim();
+            let mut heading = match_heading(part_regex, trimed_line);
+
+            if let Some(candidate) = &heading {
+                if !scan.accept(file, line_no, trimed_line, candidate, "part")? {
+                    heading = None;
+                }
+            }
+
+            scan.advance(trimed_line);
+
+            if let Some(heading) = heading {
                 if let Some(part) = self.parts.last_mut() {
-                    part.end = file.stream_position()? - line.as_bytes().len() as u64;
+                    part.end = file.stream_position()? - line.len() as u64;
                 }
 
-                self.parts.push(Part::new(
+                let mut part = Part::new(
                     self.current_part_no,
-                    cap[1].to_string(),
-                    std::mem::take(&mut line),
+                    heading.title.to_string(),
+                    line.clone(),
                     file.stream_position()?,
-                ));
+                    line_no,
+                );
+                part.heading_no = heading.no;
+
+                self.parts.push(part);
+                line.clear();
 
                 self.current_part_no += 1;
 
@@ -155,30 +266,252 @@ impl Novel {
         file.rewind()?;
 
         let mut line = String::new();
-
-        // let chapter_regex = Regex::new(&options.chapter_regex)?;
-        let chapter_regex = &options.chapter_regex;
+        let mut line_no = 0;
+        let mut scan = HeadingScan::new(&options.guards, u64::MAX);
 
         while let Ok(len) = file.read_line(&mut line) {
             if len == 0 {
                 break;
             }
 
-            if chapter_regex.is_match(line.trim()) {
-                self.parts.push(Part::new(
+            line_no += 1;
+
+            let trimed_line = line.trim();
+            let accepted = match match_chapter(&options.chapter_patterns, trimed_line) {
+                Some((_, heading)) => {
+                    scan.accept(file, line_no, trimed_line, &heading, "chapter")?
+                }
+                None => false,
+            };
+
+            if accepted {
+                let mut part = Part::new(
                     0,
                     "".into(),
                     "".into(),
-                    file.stream_position()? - line.as_bytes().len() as u64,
-                ));
+                    file.stream_position()? - line.len() as u64,
+                    0,
+                );
+                part.start_line = line_no;
+
+                self.parts.push(part);
 
                 break;
             }
 
+            scan.advance(trimed_line);
             line.clear();
         }
 
+        if self.parts.is_empty() {
+            return Err(Txt2EpubError::Structure {
+                path: options.path.clone(),
+                line: line_no,
+                message: format!(
+                    "neither part nor chapter title is found, the chapter regex is `{}`",
+                    describe(&options.chapter_patterns)
+                ),
+            });
+        }
+
         self.parts[0].end = file.seek(std::io::SeekFrom::End(0))?;
         Ok(())
     }
 }
+
+/// The metadata of the novel from, in increasing priority, `book.yaml` in its directory, the
+/// header before the first heading, `<name>.meta.yaml` next to it and `--meta`. The title falls
+/// back to the name of the novel.
+///
+/// A header that does not start with a metadata key is the beginning of the novel rather than
+/// yaml, it is kept as the preface.
+pub(crate) fn parse_metadata(header: &str, options: &ConvertOpt) -> Result<Metadata> {
+    let mut metadata = Metadata::default();
+    let dir = options.path.parent();
+
+    if let Some(dir) = dir {
+        if let Some(shared) = read_sidecar(&dir.join(SHARED_SIDECAR))? {
+            metadata.merge(shared);
+        }
+    }
+
+    if is_metadata_header(header) {
+        metadata.merge(parse_yaml(header, &options.path)?);
+    } else {
+        metadata.preface = header
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(String::from)
+            .collect();
+
+        if !metadata.preface.is_empty() {
+            info!(
+                "no metadata header, {} lines before the first heading are kept as the preface",
+                metadata.preface.len()
+            );
+        }
+    }
+
+    if let (Some(dir), Some(name)) = (dir, options.path.file_prefix()) {
+        let sidecar = dir.join(format!("{}{}", name.to_string_lossy(), SIDECAR_SUFFIX));
+
+        if let Some(own) = read_sidecar(&sidecar)? {
+            metadata.merge(own);
+        }
+    }
+
+    if !options.meta.is_empty() {
+        metadata.merge(parse_meta_flags(&options.meta, &options.path)?);
+    }
+
+    metadata.default_title(&options.name);
+
+    Ok(metadata)
+}
+
+/// The metadata shared by the novels of a directory.
+const SHARED_SIDECAR: &str = "book.yaml";
+/// `<name>.meta.yaml`, the metadata of one novel.
+const SIDECAR_SUFFIX: &str = ".meta.yaml";
+
+/// A metadata header starts with one of the keys, comments and blank lines aside.
+fn is_metadata_header(header: &str) -> bool {
+    header
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty() && !l.starts_with('#') && *l != "---")
+        .and_then(|l| l.split_once(':'))
+        .is_some_and(|(key, _)| METADATA_KEYS.contains(&key.trim()))
+}
+
+fn read_sidecar(path: &Path) -> Result<Option<Metadata>> {
+    match fs::read_to_string(path) {
+        Ok(text) => {
+            debug!("reading metadata from {}", path.display());
+            parse_yaml(&text, path).map(Some)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Txt2EpubError::io(path, e)),
+    }
+}
+
+fn parse_yaml(text: &str, path: &Path) -> Result<Metadata> {
+    if text.trim().is_empty() {
+        return Ok(Metadata::default());
+    }
+
+    let mut metadata: Metadata = serde_yaml::from_str(text).map_err(|e| {
+        let location = e.location();
+
+        Txt2EpubError::Metadata {
+            path: path.to_path_buf(),
+            line: location.as_ref().map_or(0, |l| l.line()),
+            column: location.as_ref().map_or(0, |l| l.column()),
+            message: e.to_string(),
+        }
+    })?;
+
+    metadata.normalize().map_err(|(value, message)| {
+        // point at the first line with the value, the yaml locations are gone by now.
+        let (line, column) = text
+            .lines()
+            .enumerate()
+            .find_map(|(i, l)| l.find(&value).map(|c| (i + 1, l[..c].chars().count() + 1)))
+            .unwrap_or_default();
+
+        Txt2EpubError::Metadata {
+            path: path.to_path_buf(),
+            line,
+            column,
+            message: format!("`{}`: {}", value, message),
+        }
+    })?;
+
+    Ok(metadata)
+}
+
+/// `--meta key=value`, a value in brackets is a yaml list.
+fn parse_meta_flags(meta: &[(String, String)], path: &Path) -> Result<Metadata> {
+    let mapping = meta
+        .iter()
+        .map(|(key, value)| {
+            let value = if value.starts_with('[')
+                || matches!(key.as_str(), "series_index" | "系列序号")
+            {
+                serde_yaml::from_str(value).unwrap_or_else(|_| Value::String(value.clone()))
+            } else {
+                Value::String(value.clone())
+            };
+
+            (Value::String(key.clone()), value)
+        })
+        .collect::<Mapping>();
+
+    let mut metadata: Metadata =
+        serde_yaml::from_value(Value::Mapping(mapping)).map_err(|e| Txt2EpubError::Metadata {
+            path: path.to_path_buf(),
+            line: 0,
+            column: 0,
+            message: format!("--meta: {}", e),
+        })?;
+
+    metadata
+        .normalize()
+        .map_err(|(value, message)| Txt2EpubError::Metadata {
+            path: path.to_path_buf(),
+            line: 0,
+            column: 0,
+            message: format!("--meta: `{}`: {}", value, message),
+        })?;
+
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, fs};
+
+    use super::parse_metadata;
+    use crate::cli::ConvertOpt;
+
+    #[test]
+    fn metadata_sources() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("txt2epub-sidecar-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::write(
+            dir.join("book.yaml"),
+            "作者: 共享\n出版社: 某出版社\n标签: [玄幻]\n",
+        )?;
+        fs::write(dir.join("novel.meta.yaml"), "作者: 本书\n系列: 边车\n")?;
+
+        let mut options = ConvertOpt {
+            path: dir.join("novel.txt"),
+            name: "novel".into(),
+            meta: vec![("系列".into(), "参数".into())],
+            ..Default::default()
+        };
+
+        let metadata = parse_metadata("书名: 标题\n作者: 文件头\n", &options)?;
+        assert_eq!(metadata.book_name, "标题");
+        assert_eq!(metadata.author, "本书");
+        assert_eq!(metadata.publisher.as_deref(), Some("某出版社"));
+        assert_eq!(metadata.tags, ["玄幻"]);
+        assert_eq!(metadata.series.as_deref(), Some("参数"));
+        assert!(metadata.preface.is_empty());
+
+        // prose before the first heading is the preface, the title falls back to the name.
+        options.meta.clear();
+        fs::remove_file(dir.join("novel.meta.yaml"))?;
+
+        let metadata = parse_metadata("  很久以前，有一个故事。\n\n这是第二段。\n", &options)?;
+        assert_eq!(metadata.book_name, "novel");
+        assert_eq!(metadata.author, "共享");
+        assert_eq!(metadata.preface, ["很久以前，有一个故事。", "这是第二段。"]);
+        assert_eq!(metadata.series, None);
+
+        fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+}
@@ -1,56 +1,119 @@
 use std::io::{BufRead, Seek, SeekFrom};
 
-use anyhow::Result;
-use chinese_number::{ChineseCase, ChineseCountMethod, ChineseVariant, NumberToChinese};
-use epub_builder::EpubContent;
 use log::debug;
+use rayon::prelude::*;
 use serde::Serialize;
-use tera::Context;
 
 use crate::{
     cli::ConvertOpt,
-    error::AnyError,
+    epub::EpubSink,
+    error::Result,
+    fb2::Fb2Section,
+    heading::{match_chapter, GuardCheck, HeadingGuards, HeadingMatch, SectionKind},
+    html::HtmlContent,
     novel_structure::chapter::{Line, LineType},
-    quote_replace, EpubBuilderMut, WriteToEpub, TEMPLATE_ENGINE,
+    quote_replace, render_template,
+    title_format::{self, Heading},
+    Fb2BuilderMut, HtmlBuilderMut, TxtBuilderMut, WriteToEpub, WriteToFb2, WriteToHtml, WriteToTxt,
 };
 
-use super::chapter::Chapter;
+use super::chapter::{Chapter, SerChapter};
 
 #[derive(Debug)]
 pub(crate) struct Part {
     /// if no is 0, means this part is the only one of novel
     pub no: usize,
     pub title: String,
-    #[allow(unused)]
     pub raw_title: String,
     pub chapters: Vec<Chapter>,
     pub preface: Vec<String>,
     pub start: u64,
     pub end: u64,
+    /// line number of the part title, 0 if the part has no title.
+    pub line: usize,
+    /// line number of the line at `start`.
+    pub start_line: usize,
+    /// the number written in the title, if the part regex captures it.
+    pub heading_no: Option<usize>,
     pub current_chapter_no: usize,
 }
 
 impl WriteToEpub for Part {
-    fn write_to_epub<'a>(
+    fn write_to_epub<'a, E: EpubSink>(
         self,
-        epub: EpubBuilderMut<'a>,
+        epub: &'a mut E,
         options: &mut ConvertOpt,
-    ) -> Result<EpubBuilderMut<'a>, AnyError> {
+    ) -> Result<&'a mut E> {
         let (part, content) = self.into_serialized();
 
-        debug!("writing part: {}", &part.title_string());
+        debug!("writing part: {}", part.title_string(options));
         part.write_to_epub(epub, options)?;
 
-        for c in content {
-            c.write_to_epub(epub, options)?;
+        for page in prepare_chapters(content, options, SerChapter::epub_page)? {
+            page.write_to_epub(epub, options)?;
         }
 
         Ok(epub)
     }
 }
 
+impl WriteToHtml for Part {
+    fn write_to_html<'a>(
+        self,
+        html: HtmlBuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<HtmlBuilderMut<'a>> {
+        let (part, content) = self.into_serialized();
+
+        part.write_to_html(html, options)?;
+
+        for content in prepare_chapters(content, options, SerChapter::html_content)? {
+            html.add_content(content);
+        }
+
+        Ok(html)
+    }
+}
+
+impl WriteToFb2 for Part {
+    fn write_to_fb2<'a>(
+        self,
+        fb2: Fb2BuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<Fb2BuilderMut<'a>> {
+        let (part, content) = self.into_serialized();
+
+        part.write_to_fb2(fb2, options)?;
+
+        for chapter in prepare_chapters(content, options, |c, opt| Ok(c.fb2_chapter(opt)))? {
+            fb2.add_chapter(chapter);
+        }
+
+        Ok(fb2)
+    }
+}
+
+impl WriteToTxt for Part {
+    fn write_to_txt<'a>(
+        self,
+        txt: TxtBuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<TxtBuilderMut<'a>> {
+        let (part, content) = self.into_serialized();
+
+        part.write_to_txt(txt, options)?;
+
+        for (title, lines) in prepare_chapters(content, options, |c, opt| Ok(c.txt_chapter(opt)))? {
+            txt.add_heading(&title)
+                .add_lines(lines.iter().map(|l| &l.content));
+        }
+
+        Ok(txt)
+    }
+}
+
 impl Part {
-    pub fn new(no: usize, title: String, raw_title: String, start: u64) -> Self {
+    pub fn new(no: usize, title: String, raw_title: String, start: u64, line: usize) -> Self {
         Self {
             no,
             title,
@@ -59,6 +122,9 @@ impl Part {
             preface: vec![],
             start,
             end: 0,
+            line,
+            start_line: line + 1,
+            heading_no: None,
             current_chapter_no: 1,
         }
     }
@@ -82,7 +148,7 @@ impl Part {
     where
         F: BufRead + Seek,
     {
-        let title_regex = &options.chapter_regex;
+        let patterns = &options.chapter_patterns;
 
         if options.have_section {
             debug!("scanning novel chapter of part: {}.", self.title);
@@ -93,6 +159,8 @@ impl Part {
         let mut preface = vec![];
         let mut chapter_start = false;
         let mut line = String::new();
+        let mut line_no = self.start_line;
+        let mut scan = HeadingScan::new(&options.guards, self.end);
 
         while let Ok(len) = file.read_line(&mut line) {
             // quit the loop when read to file end
@@ -101,21 +169,33 @@ impl Part {
             }
 
             let trimed_line = line.trim();
+            let offset = file.stream_position()? - line.len() as u64;
+            let mut heading = match_chapter(patterns, trimed_line);
+
+            if let Some((_, candidate)) = &heading {
+                if !scan.accept(file, line_no, trimed_line, candidate, "chapter")? {
+                    heading = None;
+                }
+            }
 
-            if let Some(cap) = title_regex.captures(trimed_line) {
+            if let Some((pattern, heading)) = heading {
                 // search for the chapter title
                 chapter_start = true;
 
-                self.patch_current_end(file.stream_position()? - line.as_bytes().len() as u64);
-
-                self.chapters.push(Chapter::new(
-                    *global_chapter_num + 1,
-                    self.current_chapter_no,
-                    self.no,
-                    cap[1].to_string(),
-                    line.clone(),
-                    file.stream_position()?,
-                ));
+                self.patch_current_end(offset);
+
+                self.chapters.push(
+                    Chapter::new(
+                        *global_chapter_num + 1,
+                        self.current_chapter_no,
+                        self.no,
+                        heading.title.to_string(),
+                        line.clone(),
+                        file.stream_position()?,
+                        line_no,
+                    )
+                    .matched_by(pattern, &heading),
+                );
 
                 *global_chapter_num += 1;
                 self.current_chapter_no += 1;
@@ -134,18 +214,23 @@ impl Part {
                 self.current_chapter_mut().content.push(Line {
                     line_type,
                     content: trimed_line.to_string(),
+                    offset,
+                    line_no,
                 })
             }
 
+            scan.advance(trimed_line);
+
             // quit the loop if read to the chapter end.
             if file.stream_position()? >= self.end {
                 break;
             }
 
             line.clear();
+            line_no += 1;
         }
 
-        self.patch_current_end(file.stream_position()? - line.as_bytes().len() as u64);
+        self.patch_current_end(file.stream_position()? - line.len() as u64);
 
         self.preface = preface;
 
@@ -158,8 +243,10 @@ impl Part {
         let Self {
             no,
             title,
+            raw_title,
             preface,
             chapters,
+            heading_no,
             ..
         } = self;
 
@@ -167,68 +254,297 @@ impl Part {
             SerPart {
                 no,
                 title,
+                raw_title: raw_title.trim().to_string(),
+                heading_no,
                 preface,
                 is_long_preface: false,
+                label: String::new(),
             },
             chapters,
         )
     }
 }
 
-fn is_divider(trimed_line: &str, d: &str) -> bool {
+/// Prepare the chapters in parallel with `f`, the results keep the order of the chapters.
+fn prepare_chapters<T, F>(chapters: Vec<Chapter>, options: &ConvertOpt, f: F) -> Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(SerChapter, &ConvertOpt) -> Result<T> + Sync,
+{
+    chapters
+        .into_par_iter()
+        .map(|c| f(c.into(), options))
+        .collect()
+}
+
+pub(crate) fn is_divider(trimed_line: &str, d: &str) -> bool {
     d.len() == trimed_line.len() && d == trimed_line
 }
 
+/// The heading guards applied while the file is scanned line by line.
+pub(crate) struct HeadingScan<'a> {
+    guards: &'a HeadingGuards,
+    check: GuardCheck<'a>,
+    blank_before: bool,
+    /// where the scanned range ends, the line after it is not read.
+    end: u64,
+}
+
+impl<'a> HeadingScan<'a> {
+    pub(crate) fn new(guards: &'a HeadingGuards, end: u64) -> Self {
+        Self {
+            guards,
+            check: guards.check(),
+            blank_before: true,
+            end,
+        }
+    }
+
+    /// Whether the guards accept the heading matched on the trimmed line, the file is positioned
+    /// after the line. `kind` names the heading in the log.
+    pub(crate) fn accept<F: BufRead + Seek>(
+        &mut self,
+        file: &mut F,
+        line_no: usize,
+        line: &str,
+        heading: &HeadingMatch,
+        kind: &str,
+    ) -> Result<bool> {
+        let blank_after = !self.guards.need_next_line()
+            || file.stream_position()? >= self.end
+            || next_line_blank(file)?;
+
+        match self
+            .check
+            .reject(line, heading, self.blank_before, blank_after)
+        {
+            Some(reason) => {
+                debug!(
+                    "line {} is not a {} heading, {}: {}",
+                    line_no, kind, reason, line
+                );
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
+
+    /// Move past the trimmed line.
+    pub(crate) fn advance(&mut self, line: &str) {
+        self.blank_before = line.is_empty();
+    }
+}
+
+/// Whether the next line is blank or missing, the position of `file` is kept.
+fn next_line_blank<F: BufRead + Seek>(file: &mut F) -> Result<bool> {
+    let position = file.stream_position()?;
+    let mut line = String::new();
+
+    file.read_line(&mut line)?;
+    file.seek(SeekFrom::Start(position))?;
+
+    Ok(line.trim().is_empty())
+}
+
 #[derive(Serialize)]
 pub struct SerPart {
     pub no: usize,
     pub title: String,
+    /// the heading line as it is in the source file.
+    pub raw_title: String,
+    pub heading_no: Option<usize>,
     pub preface: Vec<String>,
     pub is_long_preface: bool,
+    /// the number above the title, set when rendering.
+    pub label: String,
 }
 
 impl WriteToEpub for SerPart {
-    fn write_to_epub<'a>(
+    fn write_to_epub<'a, E: EpubSink>(
         self,
-        epub: EpubBuilderMut<'a>,
+        epub: &'a mut E,
         options: &mut ConvertOpt,
-    ) -> Result<EpubBuilderMut<'a>, AnyError> {
-        let title = self.title_string();
+    ) -> Result<&'a mut E> {
+        let title = self.title_string(options);
 
         if options.have_section {
-            epub.add_content(
-                EpubContent::new(
-                    format!("{:02}/intro.xhtml", self.no),
-                    self.into_html_string(options)?.as_bytes(),
+            let href = format!("{:02}/intro.xhtml", self.no);
+
+            epub.add_page(&href, &title, 1, &self.into_html_string(options)?)?;
+        }
+
+        Ok(epub)
+    }
+}
+
+impl WriteToHtml for SerPart {
+    fn write_to_html<'a>(
+        self,
+        html: HtmlBuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<HtmlBuilderMut<'a>> {
+        let title = self.title_string(options);
+
+        if options.have_section {
+            html.add_content(
+                HtmlContent::new(
+                    format!("part-{:02}", self.no),
+                    self.render(options, "part_body")?,
                 )
                 .title(title),
-            )?;
+            );
         }
 
-        Ok(epub)
+        Ok(html)
+    }
+}
+
+impl WriteToFb2 for SerPart {
+    fn write_to_fb2<'a>(
+        mut self,
+        fb2: Fb2BuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<Fb2BuilderMut<'a>> {
+        if options.have_section {
+            let title = self.title_string(options);
+            self.transform(options);
+            fb2.add_section(Fb2Section::new(title, self.preface));
+        } else {
+            fb2.add_section(Fb2Section::default());
+        }
+
+        Ok(fb2)
+    }
+}
+
+impl WriteToTxt for SerPart {
+    fn write_to_txt<'a>(
+        mut self,
+        txt: TxtBuilderMut<'a>,
+        options: &mut ConvertOpt,
+    ) -> Result<TxtBuilderMut<'a>> {
+        if options.have_section {
+            self.transform(options);
+            txt.add_heading(&self.title_string(options))
+                .add_lines(&self.preface);
+        }
+
+        Ok(txt)
     }
 }
 
 impl SerPart {
-    pub fn into_html_string(mut self, opt: &ConvertOpt) -> Result<String> {
+    pub fn into_html_string(self, opt: &ConvertOpt) -> Result<String> {
+        self.render(opt, "part")
+    }
+
+    /// Render with the given template, `part` for a whole page or `part_body` for the fragment.
+    pub fn render(mut self, opt: &ConvertOpt, template: &str) -> Result<String> {
+        self.transform(opt);
+        self.label = title_format::part_header(opt, &self.heading());
+        render_template(template, self, opt)
+    }
+
+    /// Apply the text transforms shared by every output format.
+    pub fn transform(&mut self, opt: &ConvertOpt) {
         if opt.replace_quote {
             self.preface.iter_mut().for_each(quote_replace);
             quote_replace(&mut self.title);
+            quote_replace(&mut self.raw_title);
         }
-        Ok(TEMPLATE_ENGINE.render("part", &Context::from_serialize(self)?)?)
     }
 
-    pub fn title_string(&self) -> String {
-        format!(
-            "第{}卷 {}",
-            (self.no as u128)
-                .to_chinese(
-                    ChineseVariant::Simple,
-                    ChineseCase::Lower,
-                    ChineseCountMethod::TenThousand,
+    pub fn title_string(&self, opt: &ConvertOpt) -> String {
+        title_format::part_title(opt, &self.heading())
+    }
+
+    fn heading(&self) -> Heading<'_> {
+        let no = self.heading_no.unwrap_or(self.no);
+
+        Heading {
+            no,
+            part_no: no,
+            id: self.no,
+            title: &self.title,
+            raw_title: &self.raw_title,
+            kind: SectionKind::Chapter,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::prepare_chapters;
+    use crate::{
+        cli::{ConvertOpt, OutputFormat},
+        converter::Converter,
+        novel_structure::chapter::Chapter,
+    };
+
+    const CHAPTERS: usize = 200;
+
+    #[test]
+    fn prepared_in_order() {
+        let chapters = (1..=CHAPTERS)
+            .map(|no| Chapter::new(no, no, 1, no.to_string(), String::new(), 0, no))
+            .collect::<Vec<_>>();
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+
+        // the first chapters take the longest, so they would finish last.
+        let prepared = pool
+            .install(|| {
+                prepare_chapters(chapters, &ConvertOpt::default(), |chapter, _| {
+                    thread::sleep(Duration::from_micros((CHAPTERS - chapter.no) as u64 * 20));
+                    Ok(chapter.title)
+                })
+            })
+            .unwrap();
+
+        assert_eq!(
+            prepared,
+            (1..=CHAPTERS).map(|no| no.to_string()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn written_in_order() {
+        let text = (1..=CHAPTERS)
+            .map(|no| {
+                format!(
+                    "第{}章 标题{}\n\n{}\n\n",
+                    no,
+                    no,
+                    "正文。".repeat(CHAPTERS - no + 1)
                 )
-                .unwrap(),
-            self.title
-        )
+            })
+            .collect::<String>();
+
+        let mut converter = Converter::new().format(OutputFormat::Txt);
+        let book = converter
+            .parse_bytes(format!("第一卷 开始\n\n{}", text).as_bytes())
+            .unwrap();
+
+        let mut out = vec![];
+        converter.write(book, &mut out).unwrap();
+
+        let titles = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .filter(|l| l.starts_with('第') && l.contains("章"))
+            .map(|l| l.split(' ').nth(1).unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            titles,
+            (1..=CHAPTERS)
+                .map(|no| format!("标题{}", no))
+                .collect::<Vec<_>>()
+        );
     }
 }
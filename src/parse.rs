@@ -1,11 +1,17 @@
-use std::io::{BufRead, Seek};
+use std::io::{BufRead, Cursor, Seek};
 
-use anyhow::Result;
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8};
 use log::debug;
 
-use crate::{cli::ConvertOpt, novel_structure::novel::Novel, WriteToEpub};
+use crate::{
+    cleanup::clean_novel,
+    cli::ConvertOpt,
+    error::{Result, Txt2EpubError},
+    novel_structure::novel::Novel,
+};
 
-pub(crate) fn parse_txt<F>(file: &mut F, options: &mut ConvertOpt) -> Result<impl WriteToEpub>
+pub(crate) fn parse_txt<F>(file: &mut F, options: &mut ConvertOpt) -> Result<Novel>
 where
     F: BufRead + Seek,
 {
@@ -16,5 +22,67 @@ where
     novel.scan_metadata(file, options)?;
     novel.scan_parts(file, options)?;
 
+    if options.clean.enabled {
+        options.removed = clean_novel(&mut novel, &options.clean);
+    }
+
     Ok(novel)
 }
+
+/// Decode the text into utf-8, guessing the encoding when it is not utf-8.
+///
+/// A few malformed bytes are replaced with a warning, but if more than 1% of the text is
+/// malformed the guess is most likely wrong and it is an error.
+pub(crate) fn decode_txt(
+    bytes: &[u8],
+    options: &mut ConvertOpt,
+) -> Result<(Cursor<Vec<u8>>, &'static Encoding)> {
+    let encoding = detect_encoding(bytes, true);
+
+    // `decode` also strips the BOM, which may point to another encoding.
+    let (text, encoding, had_errors) = encoding.decode(bytes);
+
+    if had_errors {
+        let replaced = text.chars().filter(|&c| c == '\u{FFFD}').count();
+
+        if replaced * 100 > text.chars().count() {
+            return Err(Txt2EpubError::Encoding {
+                path: options.path.clone(),
+                encoding: encoding.name().to_string(),
+                replaced,
+            });
+        }
+
+        options.warn(format!(
+            "`{}` is not valid {}, {} malformed characters are replaced.",
+            options.path.display(),
+            encoding.name(),
+            replaced
+        ));
+    }
+
+    debug!("detected encoding: {}", encoding.name());
+
+    Ok((Cursor::new(text.into_owned().into_bytes()), encoding))
+}
+
+/// Guess the encoding from the text, or from its first bytes if `complete` is false.
+pub(crate) fn detect_encoding(bytes: &[u8], complete: bool) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+
+    let utf8 = match std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        // a prefix may end in the middle of a character.
+        Err(e) => !complete && e.error_len().is_none(),
+    };
+
+    if utf8 {
+        UTF_8
+    } else {
+        let mut detector = EncodingDetector::new();
+        detector.feed(bytes, complete);
+        detector.guess(None, true)
+    }
+}
@@ -0,0 +1,539 @@
+//! Single pass parsing for huge files, parts and chapters are handed out as soon as they end.
+//!
+//! Only the current chapter is kept in memory, the epub writer then keeps its TOC entry, see
+//! [`crate::epub::EpubStream`]. Unlike [`crate::parse::parse_txt`], chapters before the first part
+//! title are kept in a part without title, numbered 0.
+
+use std::io::Read;
+
+use encoding_rs::{CoderResult, Decoder, Encoding};
+use log::debug;
+
+use crate::{
+    cli::ConvertOpt,
+    error::{Result, Txt2EpubError},
+    heading::{describe, is_chapter, match_chapter, match_heading},
+    novel_structure::{
+        chapter::{Chapter, Line, LineType},
+        novel::parse_metadata,
+        part::{is_divider, Part},
+        Metadata,
+    },
+    parse::detect_encoding,
+};
+
+/// Bytes read at once, the encoding is guessed from the first chunk.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Decodes the input into utf-8 lines chunk by chunk.
+pub(crate) struct LineReader<R> {
+    inner: R,
+    encoding: &'static Encoding,
+    decoder: Decoder,
+    text: String,
+    /// consumed bytes of `text`.
+    pos: usize,
+    eof: bool,
+    /// byte offset of the next line in the decoded text.
+    offset: u64,
+    line_no: usize,
+    chars: usize,
+    replaced: usize,
+}
+
+impl<R: Read> LineReader<R> {
+    pub(crate) fn new(mut inner: R) -> std::io::Result<Self> {
+        let mut prefix = vec![0; CHUNK_SIZE];
+        let len = read_full(&mut inner, &mut prefix)?;
+        prefix.truncate(len);
+
+        let encoding = detect_encoding(&prefix, len < CHUNK_SIZE);
+
+        debug!("detected encoding: {}", encoding.name());
+
+        let mut reader = Self {
+            inner,
+            encoding,
+            decoder: encoding.new_decoder_with_bom_removal(),
+            text: String::new(),
+            pos: 0,
+            eof: false,
+            offset: 0,
+            line_no: 0,
+            chars: 0,
+            replaced: 0,
+        };
+
+        reader.decode(&prefix, len == 0);
+
+        Ok(reader)
+    }
+
+    pub(crate) fn encoding(&self) -> &'static Encoding {
+        self.encoding
+    }
+
+    /// Byte offset of the next line in the decoded text.
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Read the next line with its line ending into `line`, `None` at the end.
+    pub(crate) fn read_line(&mut self, line: &mut String) -> std::io::Result<Option<usize>> {
+        loop {
+            if let Some(i) = self.text[self.pos..].find('\n') {
+                return Ok(Some(self.take(line, self.pos + i + 1)));
+            }
+
+            if self.eof {
+                return Ok((self.pos < self.text.len()).then(|| self.take(line, self.text.len())));
+            }
+
+            self.fill()?;
+        }
+    }
+
+    /// Error if too many characters are malformed, see [`crate::parse::decode_txt`].
+    pub(crate) fn finish(&self, options: &mut ConvertOpt) -> Result<()> {
+        if self.replaced == 0 {
+            return Ok(());
+        }
+
+        if self.replaced * 100 > self.chars {
+            return Err(Txt2EpubError::Encoding {
+                path: options.path.clone(),
+                encoding: self.encoding.name().to_string(),
+                replaced: self.replaced,
+            });
+        }
+
+        options.warn(format!(
+            "`{}` is not valid {}, {} malformed characters are replaced.",
+            options.path.display(),
+            self.encoding.name(),
+            self.replaced
+        ));
+
+        Ok(())
+    }
+
+    fn take(&mut self, line: &mut String, end: usize) -> usize {
+        let len = end - self.pos;
+
+        line.push_str(&self.text[self.pos..end]);
+        self.pos = end;
+        self.offset += len as u64;
+        self.line_no += 1;
+
+        len
+    }
+
+    fn fill(&mut self) -> std::io::Result<()> {
+        let mut buf = vec![0; CHUNK_SIZE];
+        let len = read_full(&mut self.inner, &mut buf)?;
+
+        self.text.drain(..self.pos);
+        self.pos = 0;
+        self.decode(&buf[..len], len == 0);
+
+        Ok(())
+    }
+
+    fn decode(&mut self, mut bytes: &[u8], last: bool) {
+        let start = self.text.len();
+
+        loop {
+            self.text.reserve(
+                self.decoder
+                    .max_utf8_buffer_length(bytes.len())
+                    .unwrap_or(bytes.len() * 3 + 16),
+            );
+
+            let (result, read, _) = self.decoder.decode_to_string(bytes, &mut self.text, last);
+            bytes = &bytes[read..];
+
+            if result == CoderResult::InputEmpty {
+                break;
+            }
+        }
+
+        let decoded = &self.text[start..];
+        self.chars += decoded.chars().count();
+        self.replaced += decoded.chars().filter(|&c| c == '\u{FFFD}').count();
+        self.eof = last;
+    }
+}
+
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(len)
+}
+
+pub(crate) enum Event {
+    /// a part with its preface, its chapters follow.
+    Part(Part),
+    Chapter(Chapter),
+}
+
+/// Reads the novel in one pass, see the module doc.
+pub(crate) struct StreamParser<'a, R> {
+    lines: LineReader<R>,
+    options: &'a ConvertOpt,
+    /// a title line read ahead, with its offset and line number.
+    heading: Option<(String, u64, usize)>,
+    /// the part whose preface is being read.
+    part: Option<Part>,
+    part_no: usize,
+    chapter_no: usize,
+    chapter_id: usize,
+}
+
+impl<'a, R: Read> StreamParser<'a, R> {
+    /// Read the metadata header, the parser then stands at the first title.
+    pub(crate) fn new(
+        mut lines: LineReader<R>,
+        options: &'a ConvertOpt,
+    ) -> Result<(Self, Metadata)> {
+        let mut header = String::new();
+        let mut line = String::new();
+        let mut heading = None;
+
+        loop {
+            let offset = lines.offset();
+
+            if lines.read_line(&mut line)?.is_none() {
+                break;
+            }
+
+            let trimed_line = line.trim();
+
+            if options.part_regex.is_match(trimed_line)
+                || is_chapter(&options.chapter_patterns, trimed_line)
+            {
+                heading = Some((std::mem::take(&mut line), offset, lines.line_no));
+                break;
+            }
+
+            header.push_str(&line);
+            line.clear();
+        }
+
+        if heading.is_none() {
+            return Err(Txt2EpubError::Structure {
+                path: options.path.clone(),
+                line: lines.line_no,
+                message: format!(
+                    "neither part nor chapter title is found, the chapter regex is `{}`",
+                    describe(&options.chapter_patterns)
+                ),
+            });
+        }
+
+        let metadata = parse_metadata(&header, options)?;
+
+        Ok((
+            Self {
+                lines,
+                options,
+                heading,
+                part: None,
+                part_no: 1,
+                chapter_no: 1,
+                chapter_id: 0,
+            },
+            metadata,
+        ))
+    }
+
+    pub(crate) fn lines(&self) -> &LineReader<R> {
+        &self.lines
+    }
+
+    fn next_event(&mut self) -> Result<Option<Event>> {
+        loop {
+            let Some((line, offset, line_no)) = self.next_line()? else {
+                return Ok(self.part.take().map(Event::Part));
+            };
+
+            let trimed_line = line.trim();
+
+            if let Some(heading) = match_heading(&self.options.part_regex, trimed_line) {
+                if let Some(part) = self.part.take() {
+                    // a part without chapter.
+                    self.heading = Some((line, offset, line_no));
+                    return Ok(Some(Event::Part(part)));
+                }
+
+                let mut part = Part::new(
+                    self.part_no,
+                    heading.title.to_string(),
+                    line.clone(),
+                    self.lines.offset(),
+                    line_no,
+                );
+                part.heading_no = heading.no;
+
+                self.part = Some(part);
+                self.part_no += 1;
+                self.chapter_no = 1;
+            } else if let Some((pattern, heading)) =
+                match_chapter(&self.options.chapter_patterns, trimed_line)
+            {
+                if let Some(part) = self.part.take() {
+                    self.heading = Some((line, offset, line_no));
+                    return Ok(Some(Event::Part(part)));
+                }
+
+                let chapter = Chapter::new(
+                    self.chapter_id + 1,
+                    self.chapter_no,
+                    self.part_no - 1,
+                    heading.title.to_string(),
+                    line.clone(),
+                    self.lines.offset(),
+                    line_no,
+                )
+                .matched_by(pattern, &heading);
+
+                self.chapter_id += 1;
+                self.chapter_no += 1;
+
+                return self.read_chapter(chapter).map(|c| Some(Event::Chapter(c)));
+            } else if let Some(part) = self.part.as_mut() {
+                if !trimed_line.is_empty() {
+                    part.preface.push(trimed_line.to_string());
+                }
+            }
+        }
+    }
+
+    /// Read the content until the next title.
+    fn read_chapter(&mut self, mut chapter: Chapter) -> Result<Chapter> {
+        loop {
+            let offset = self.lines.offset();
+
+            let Some((line, _, line_no)) = self.next_line()? else {
+                chapter.end = self.lines.offset();
+                return Ok(chapter);
+            };
+
+            let trimed_line = line.trim();
+
+            if self.options.part_regex.is_match(trimed_line)
+                || is_chapter(&self.options.chapter_patterns, trimed_line)
+            {
+                chapter.end = offset;
+                self.heading = Some((line, offset, line_no));
+                return Ok(chapter);
+            }
+
+            if trimed_line.is_empty() {
+                continue;
+            }
+
+            let line_type = if self
+                .options
+                .divider
+                .iter()
+                .any(|d| is_divider(trimed_line, d))
+            {
+                LineType::Divider
+            } else {
+                LineType::Line
+            };
+
+            chapter.content.push(Line {
+                line_type,
+                content: trimed_line.to_string(),
+                offset,
+                line_no,
+            });
+        }
+    }
+
+    fn next_line(&mut self) -> Result<Option<(String, u64, usize)>> {
+        if let Some(heading) = self.heading.take() {
+            return Ok(Some(heading));
+        }
+
+        let offset = self.lines.offset();
+        let mut line = String::new();
+
+        Ok(self
+            .lines
+            .read_line(&mut line)?
+            .map(|_| (line, offset, self.lines.line_no)))
+    }
+}
+
+impl<R: Read> Iterator for StreamParser<'_, R> {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use encoding_rs::{GBK, UTF_8};
+
+    use super::{Event, LineReader, StreamParser, CHUNK_SIZE};
+    use crate::{cli::ConvertOpt, novel_structure::chapter::LineType};
+
+    fn read_lines<R: std::io::Read>(reader: &mut LineReader<R>) -> Vec<(String, u64)> {
+        let mut lines = vec![];
+
+        loop {
+            let offset = reader.offset();
+            let mut line = String::new();
+
+            if reader.read_line(&mut line).unwrap().is_none() {
+                return lines;
+            }
+
+            lines.push((line, offset));
+        }
+    }
+
+    #[test]
+    fn lines_across_chunks() {
+        let long = "长".repeat(CHUNK_SIZE / 3 + 7);
+        let text = format!("\u{feff}第一行\r\n{}\n\n最后一行", long);
+        let mut reader = LineReader::new(text.as_bytes()).unwrap();
+
+        assert_eq!(reader.encoding(), UTF_8);
+
+        let lines = read_lines(&mut reader);
+        let first = "第一行\r\n".len() as u64;
+
+        assert_eq!(
+            lines,
+            [
+                ("第一行\r\n".to_string(), 0),
+                (format!("{}\n", long), first),
+                ("\n".to_string(), first + long.len() as u64 + 1),
+                ("最后一行".to_string(), first + long.len() as u64 + 2),
+            ]
+        );
+        assert_eq!(reader.line_no, 4);
+        assert!(reader.finish(&mut ConvertOpt::default()).is_ok());
+    }
+
+    #[test]
+    fn detect_and_replace() {
+        let (gbk, _, _) = GBK.encode("第一章 开始\n正文，很长的正文。\n");
+        let mut reader = LineReader::new(&gbk[..]).unwrap();
+
+        assert_eq!(reader.encoding(), GBK);
+        assert_eq!(read_lines(&mut reader)[1].0, "正文，很长的正文。\n");
+
+        // the encoding is guessed from the first chunk, the malformed bytes come after it.
+        let mut text = "正文。".repeat(CHUNK_SIZE / 9 + 1).into_bytes();
+        text.push(0xff);
+        let mut reader = LineReader::new(&text[..]).unwrap();
+        read_lines(&mut reader);
+
+        let mut options = ConvertOpt::default();
+        assert!(reader.finish(&mut options).is_ok());
+        assert_eq!(options.warnings.len(), 1);
+
+        let mut text = vec![b'a'; CHUNK_SIZE];
+        text.extend([0xff; 1000]);
+        let mut reader = LineReader::new(&text[..]).unwrap();
+        read_lines(&mut reader);
+
+        assert_eq!(reader.encoding(), UTF_8);
+        assert!(reader.finish(&mut options).is_err());
+    }
+
+    #[test]
+    fn events() -> Result<(), Box<dyn Error>> {
+        let text = "书名: 测试
+作者: 某人
+
+第一章 序
+
+序的正文。
+
+第一卷 开始
+
+卷首语。
+
+第一章 相遇
+
+正文一。
+***
+
+第二章 别离
+第二卷 空卷
+第三卷 结束
+第一章 重逢
+正文三。";
+        let options = ConvertOpt {
+            divider: vec!["***".into()],
+            ..Default::default()
+        };
+        let lines = LineReader::new(text.as_bytes())?;
+        let (parser, metadata) = StreamParser::new(lines, &options)?;
+
+        let package = metadata.package_metadata();
+        assert_eq!(
+            (package.title.as_str(), package.author.as_str()),
+            ("测试", "某人")
+        );
+
+        let events = parser
+            .map(|event| {
+                event.map(|event| match event {
+                    Event::Part(part) => {
+                        assert!(part.chapters.is_empty());
+                        format!("part {} {} {:?}", part.no, part.title, part.preface)
+                    }
+                    Event::Chapter(chapter) => {
+                        let content = chapter
+                            .content
+                            .iter()
+                            .map(|l| match l.line_type {
+                                LineType::Divider => format!("[{}]", l.content),
+                                _ => format!("{}@{}", l.content, l.line_no),
+                            })
+                            .collect::<Vec<_>>();
+
+                        format!(
+                            "chapter {}/{}/{} {} {:?}",
+                            chapter.id, chapter.part_no, chapter.no, chapter.title, content
+                        )
+                    }
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(
+            events,
+            [
+                r#"chapter 1/0/1 序 ["序的正文。@6"]"#,
+                r#"part 1 开始 ["卷首语。"]"#,
+                r#"chapter 2/1/1 相遇 ["正文一。@14", "[***]"]"#,
+                r#"chapter 3/1/2 别离 []"#,
+                r#"part 2 空卷 []"#,
+                r#"part 3 结束 []"#,
+                r#"chapter 4/3/1 重逢 ["正文三。@21"]"#,
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+//! Format strings for the chapter and part titles, such as `Chapter {no}: {title}` or
+//! `{no:03} {title}`, set by `--title-format`.
+//!
+//! The placeholders are `{no}`, `{part_no}`, `{id}`, the number of the chapter in the whole
+//! novel, `{title}` and `{raw}`, the heading line as it is in the source file. `{no}` is the
+//! number written in the heading when the regex captures it with a `no` group. A number can take
+//! a numeral style, `{no:roman}`, or a width to pad arabic numerals with zeros, `{no:03}`. Without
+//! style it is written in the numerals of the locale. `{{` and `}}` are literal braces.
+
+use std::str::FromStr;
+
+use crate::{
+    cli::{ConvertOpt, HeadingStyle},
+    heading::SectionKind,
+    locale::Numerals,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TitleKind {
+    /// chapter titles in the TOC and the headings of the txt and fb2 output
+    Chapter,
+    /// part titles in the TOC and the headings of the txt and fb2 output
+    Part,
+    /// the number above the title on a chapter page
+    ChapterHeader,
+    /// the number above the title on a part page
+    PartHeader,
+    /// the title of a chapter page and, when given, its TOC entry, numbered through the whole
+    /// novel
+    Global,
+}
+
+impl FromStr for TitleKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chapter" => Ok(TitleKind::Chapter),
+            "part" => Ok(TitleKind::Part),
+            "chapter-header" => Ok(TitleKind::ChapterHeader),
+            "part-header" => Ok(TitleKind::PartHeader),
+            "global" => Ok(TitleKind::Global),
+            _ => Err(format!(
+                "unknown title `{}`, expected chapter, part, chapter-header, part-header or global",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Number {
+    No,
+    PartNo,
+    Id,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    /// the numerals of the locale.
+    Locale,
+    Numerals(Numerals),
+    /// arabic numerals padded with zeros to the width.
+    Padded(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    Title,
+    Raw,
+    Number(Number, Style),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TitleFormat {
+    pieces: Vec<Piece>,
+}
+
+/// The values a title is formatted with.
+pub(crate) struct Heading<'a> {
+    pub no: usize,
+    pub part_no: usize,
+    pub id: usize,
+    pub title: &'a str,
+    pub raw_title: &'a str,
+    /// titles of other kinds than chapter are not numbered.
+    pub kind: SectionKind,
+}
+
+impl FromStr for TitleFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pieces = vec![];
+        let mut text = String::new();
+        let mut chars = s.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| format!("unclosed `{{` in `{}`", s))?;
+
+                    if !text.is_empty() {
+                        pieces.push(Piece::Text(std::mem::take(&mut text)));
+                    }
+
+                    pieces.push(parse_placeholder(&rest[..end])?);
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(format!("unmatched `}}` in `{}`, write `}}}}`", s)),
+                c => text.push(c),
+            }
+        }
+
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+
+        Ok(Self { pieces })
+    }
+}
+
+fn parse_placeholder(placeholder: &str) -> Result<Piece, String> {
+    let (name, style) = match placeholder.split_once(':') {
+        Some((name, style)) => (name.trim(), Some(style.trim())),
+        None => (placeholder.trim(), None),
+    };
+
+    let number = match (name, style) {
+        ("no", _) => Number::No,
+        ("part_no", _) => Number::PartNo,
+        ("id", _) => Number::Id,
+        ("title", None) => return Ok(Piece::Title),
+        ("raw", None) => return Ok(Piece::Raw),
+        ("title" | "raw", Some(_)) => {
+            return Err(format!("`{{{}}}` takes no numeral style", name));
+        }
+        _ => {
+            return Err(format!(
+                "unknown placeholder `{{{}}}`, expected no, part_no, id, title or raw",
+                name
+            ))
+        }
+    };
+
+    let Some(style) = style else {
+        return Ok(Piece::Number(number, Style::Locale));
+    };
+
+    let style = match style {
+        "arabic" => Style::Numerals(Numerals::Arabic),
+        "chinese" => Style::Numerals(Numerals::Chinese),
+        "traditional" => Style::Numerals(Numerals::TraditionalChinese),
+        "kanji" => Style::Numerals(Numerals::Kanji),
+        "roman" => Style::Numerals(Numerals::Roman),
+        width if !width.is_empty() && width.bytes().all(|b| b.is_ascii_digit()) => {
+            Style::Padded(width.parse().map_err(|_| format!("invalid width `{}`", width))?)
+        }
+        _ => {
+            return Err(format!(
+                "unknown numeral style `{}`, expected arabic, chinese, traditional, kanji, roman or a width such as 03",
+                style
+            ))
+        }
+    };
+
+    Ok(Piece::Number(number, style))
+}
+
+impl TitleFormat {
+    /// `no` and `id` are written in `numerals` by default, `part_no` in `part_numerals`.
+    pub(crate) fn format(
+        &self,
+        heading: &Heading,
+        numerals: Numerals,
+        part_numerals: Numerals,
+    ) -> String {
+        let mut out = String::new();
+
+        for piece in &self.pieces {
+            let (no, style, default) = match piece {
+                Piece::Text(text) => {
+                    out.push_str(text);
+                    continue;
+                }
+                Piece::Title => {
+                    out.push_str(heading.title);
+                    continue;
+                }
+                Piece::Raw => {
+                    out.push_str(heading.raw_title.trim());
+                    continue;
+                }
+                Piece::Number(Number::No, style) => (heading.no, style, numerals),
+                Piece::Number(Number::PartNo, style) => (heading.part_no, style, part_numerals),
+                Piece::Number(Number::Id, style) => (heading.id, style, numerals),
+            };
+
+            match style {
+                Style::Locale => out.push_str(&default.format(no)),
+                Style::Numerals(numerals) => out.push_str(&numerals.format(no)),
+                Style::Padded(width) => out.push_str(&format!("{:0width$}", no, width = width)),
+            }
+        }
+
+        out
+    }
+}
+
+/// The formats set by `--title-format`, the locale and the [`HeadingStyle`] decide the titles
+/// without one.
+#[derive(Debug, Clone, Default)]
+pub struct TitleFormats {
+    pub chapter: Option<TitleFormat>,
+    pub part: Option<TitleFormat>,
+    pub chapter_header: Option<TitleFormat>,
+    pub part_header: Option<TitleFormat>,
+    pub global: Option<TitleFormat>,
+}
+
+impl TitleFormats {
+    pub fn set(&mut self, kind: TitleKind, format: TitleFormat) {
+        let field = match kind {
+            TitleKind::Chapter => &mut self.chapter,
+            TitleKind::Part => &mut self.part,
+            TitleKind::ChapterHeader => &mut self.chapter_header,
+            TitleKind::PartHeader => &mut self.part_header,
+            TitleKind::Global => &mut self.global,
+        };
+
+        *field = Some(format);
+    }
+}
+
+/// The chapter title in the TOC and the headings of the txt and fb2 output, prologues, extras
+/// and the other unnumbered kinds keep their heading.
+pub(crate) fn chapter_title(opt: &ConvertOpt, heading: &Heading) -> String {
+    let locale = opt.locale;
+
+    match (&opt.title_formats.chapter, opt.heading_style) {
+        (Some(format), _) => {
+            format.format(heading, locale.chapter_numerals(), locale.part_numerals())
+        }
+        (None, HeadingStyle::Raw) => heading.raw_title.trim().to_string(),
+        (None, _) if heading.kind != SectionKind::Chapter => heading.raw_title.trim().to_string(),
+        (None, HeadingStyle::Normalized) => locale.chapter_title(heading.no, heading.title),
+    }
+}
+
+/// The part title in the TOC and the headings of the txt and fb2 output.
+pub(crate) fn part_title(opt: &ConvertOpt, heading: &Heading) -> String {
+    let locale = opt.locale;
+
+    match (&opt.title_formats.part, opt.heading_style) {
+        (Some(format), _) => format.format(heading, locale.part_numerals(), locale.part_numerals()),
+        (None, HeadingStyle::Raw) => heading.raw_title.trim().to_string(),
+        (None, HeadingStyle::Normalized) => locale.part_title(heading.no, heading.title),
+    }
+}
+
+/// The number above the title on a chapter page, none for raw headings which have their own.
+pub(crate) fn chapter_header(opt: &ConvertOpt, heading: &Heading) -> String {
+    let locale = opt.locale;
+
+    match (&opt.title_formats.chapter_header, opt.heading_style) {
+        (Some(format), _) => {
+            format.format(heading, locale.chapter_numerals(), locale.part_numerals())
+        }
+        (None, HeadingStyle::Raw) => String::new(),
+        (None, _) if heading.kind != SectionKind::Chapter => String::new(),
+        (None, HeadingStyle::Normalized) => locale.chapter_label(heading.part_no, heading.no),
+    }
+}
+
+/// The number above the title on a part page, none for raw headings which have their own.
+pub(crate) fn part_header(opt: &ConvertOpt, heading: &Heading) -> String {
+    let locale = opt.locale;
+
+    match (&opt.title_formats.part_header, opt.heading_style) {
+        (Some(format), _) => format.format(heading, locale.part_numerals(), locale.part_numerals()),
+        (None, HeadingStyle::Raw) => String::new(),
+        (None, HeadingStyle::Normalized) => locale.part_label(heading.no),
+    }
+}
+
+pub(crate) fn global_title(opt: &ConvertOpt, heading: &Heading) -> String {
+    let locale = opt.locale;
+
+    match &opt.title_formats.global {
+        Some(format) => format.format(heading, locale.chapter_numerals(), locale.part_numerals()),
+        None if heading.kind != SectionKind::Chapter => heading.raw_title.trim().to_string(),
+        None => locale.chapter_title(heading.id, heading.title),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Heading, TitleFormat};
+    use crate::{heading::SectionKind, locale::Numerals};
+
+    #[test]
+    fn format() {
+        let heading = Heading {
+            no: 12,
+            part_no: 2,
+            id: 40,
+            title: "Title",
+            raw_title: "  第十二章 Title\n",
+            kind: SectionKind::Chapter,
+        };
+        let format = |s: &str| {
+            s.parse::<TitleFormat>()
+                .unwrap()
+                .format(&heading, Numerals::Chinese, Numerals::Roman)
+        };
+
+        assert_eq!(format("Chapter {no:arabic}: {title}"), "Chapter 12: Title");
+        assert_eq!(format("{no:03} {title}"), "012 Title");
+        assert_eq!(format("{part_no}-{no} {{{id}}}"), "II-十二 {四十}");
+        assert_eq!(format("{raw}"), "第十二章 Title");
+
+        assert!("{title:roman}".parse::<TitleFormat>().is_err());
+        assert!("{name}".parse::<TitleFormat>().is_err());
+        assert!("{no".parse::<TitleFormat>().is_err());
+        assert!("no}".parse::<TitleFormat>().is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use std::io::Write;
+
+use crate::error::Result;
+
+/// Collects the normalised novel back into plain text.
+#[derive(Default)]
+pub struct TxtBuilder {
+    text: String,
+    /// whether anything has been flushed, the leading blank lines are only trimmed once.
+    flushed: bool,
+}
+
+impl TxtBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The yaml header, same as the one `txt2epub` reads.
+    pub fn metadata(&mut self, header: &str) -> &mut Self {
+        self.text.push_str(header.trim_end());
+        self.text.push('\n');
+        self
+    }
+
+    /// A part or chapter heading, surrounded by one blank line.
+    pub fn add_heading(&mut self, heading: &str) -> &mut Self {
+        self.text.push('\n');
+        self.text.push_str(heading.trim());
+        self.text.push_str("\n\n");
+        self
+    }
+
+    pub fn add_lines<I, S>(&mut self, lines: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for line in lines {
+            let line = line.as_ref().trim();
+
+            if !line.is_empty() {
+                self.text.push_str(line);
+                self.text.push('\n');
+            }
+        }
+
+        self
+    }
+
+    pub fn generate<W: Write>(&self, mut out: W) -> Result<()> {
+        out.write_all(self.text.trim_start().as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Write out what has been collected so far and clear it, for streaming output.
+    pub fn flush_to<W: Write>(&mut self, mut out: W) -> Result<()> {
+        let text = if self.flushed {
+            &self.text[..]
+        } else {
+            self.text.trim_start()
+        };
+
+        out.write_all(text.as_bytes())?;
+
+        self.flushed |= !text.is_empty();
+        self.text.clear();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use crate::{cli::OutputFormat, converter::Converter};
+
+    fn to_txt(text: &str) -> Result<String, Box<dyn Error>> {
+        let mut converter = Converter::new().format(OutputFormat::Txt);
+        let book = converter.parse_bytes(text.as_bytes())?;
+
+        let mut out = vec![];
+        converter.write(book, &mut out)?;
+
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn round_trip() -> Result<(), Box<dyn Error>> {
+        let txt = to_txt(
+            "\n第一卷 开始\n\n\n第一章 相遇\n  正文一。\n\n\n正文二。\n第二章 别离\n\n正文三。\n",
+        )?;
+
+        let headings = txt
+            .lines()
+            .filter(|l| l.starts_with('第'))
+            .collect::<Vec<_>>();
+        assert_eq!(headings, ["第一卷 开始", "第一章 相遇", "第二章 别离"]);
+        assert!(txt.contains("\n\n第一章 相遇\n\n正文一。\n正文二。\n\n第二章 别离\n\n正文三。\n"));
+        assert!(!txt.starts_with('\n'));
+
+        assert_eq!(to_txt(&txt)?, txt);
+
+        Ok(())
+    }
+}
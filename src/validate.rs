@@ -0,0 +1,564 @@
+//! A native check of the generated EPUB, covering the structural parts of epubcheck.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs::File,
+    io::{BufReader, Read, Seek},
+    path::Path,
+};
+
+use roxmltree::{Document, Node, ParsingOptions};
+use serde::Serialize;
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::error::{Result, Txt2EpubError};
+
+const MIMETYPE: &str = "application/epub+zip";
+const CONTAINER: &str = "META-INF/container.xml";
+const XHTML: &str = "application/xhtml+xml";
+const NCX: &str = "application/x-dtbncx+xml";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    /// the file inside the epub, empty if the problem is about the whole archive.
+    pub file: String,
+    pub message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.file.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.file, self.message)
+        }
+    }
+}
+
+/// Validate the epub file at `path`.
+pub fn validate_path(path: impl AsRef<Path>) -> Result<Vec<Problem>> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| Txt2EpubError::io(path, e))?;
+
+    validate_epub(BufReader::new(file)).map_err(|e| Txt2EpubError::io(path, e))
+}
+
+/// Validate an epub, an empty list means no problem found.
+pub fn validate_epub<R: Read + Seek>(reader: R) -> std::io::Result<Vec<Problem>> {
+    let mut validator = match Validator::new(reader) {
+        Ok(validator) => validator,
+        Err(zip::result::ZipError::Io(e)) => return Err(e),
+        Err(e) => {
+            return Ok(vec![Problem {
+                file: String::new(),
+                message: format!("not a zip archive, {}", e),
+            }])
+        }
+    };
+
+    validator.check_mimetype()?;
+
+    if let Some(opf) = validator.check_container()? {
+        validator.check_package(&opf)?;
+    }
+
+    Ok(validator.problems)
+}
+
+struct Validator<R> {
+    zip: ZipArchive<R>,
+    names: HashSet<String>,
+    problems: Vec<Problem>,
+}
+
+impl<R: Read + Seek> Validator<R> {
+    fn new(reader: R) -> zip::result::ZipResult<Self> {
+        let zip = ZipArchive::new(reader)?;
+        let names = zip.file_names().map(String::from).collect();
+
+        Ok(Self {
+            zip,
+            names,
+            problems: vec![],
+        })
+    }
+
+    fn problem(&mut self, file: &str, message: impl Into<String>) {
+        self.problems.push(Problem {
+            file: file.to_string(),
+            message: message.into(),
+        });
+    }
+
+    fn read(&mut self, name: &str) -> std::io::Result<Option<String>> {
+        let mut file = match self.zip.by_name(name) {
+            Ok(file) => file,
+            Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+            Err(zip::result::ZipError::Io(e)) => return Err(e),
+            Err(e) => return Err(std::io::Error::other(e)),
+        };
+
+        let mut content = String::new();
+
+        if file.read_to_string(&mut content).is_err() {
+            drop(file);
+            self.problem(name, "not valid utf-8");
+            return Ok(None);
+        }
+
+        Ok(Some(content))
+    }
+
+    /// `mimetype` must be the first entry, stored uncompressed.
+    fn check_mimetype(&mut self) -> std::io::Result<()> {
+        let first = self
+            .zip
+            .by_index(0)
+            .map(|file| (file.name().to_string(), file.compression()));
+
+        let Ok((name, compression)) = first else {
+            self.problem("mimetype", "the archive is empty");
+            return Ok(());
+        };
+
+        if name != "mimetype" {
+            self.problem(
+                "mimetype",
+                format!("must be the first entry, found `{}`", name),
+            );
+        } else if compression != CompressionMethod::Stored {
+            self.problem("mimetype", "must be stored uncompressed");
+        }
+
+        match self.read("mimetype")? {
+            Some(content) if content == MIMETYPE => {}
+            Some(content) => self.problem(
+                "mimetype",
+                format!("must be `{}`, found `{}`", MIMETYPE, content),
+            ),
+            None => self.problem("mimetype", "missing"),
+        }
+
+        Ok(())
+    }
+
+    /// The path of the package document listed in `META-INF/container.xml`.
+    fn check_container(&mut self) -> std::io::Result<Option<String>> {
+        let Some(container) = self.read(CONTAINER)? else {
+            self.problem(CONTAINER, "missing");
+            return Ok(None);
+        };
+
+        let Some(doc) = self.parse(CONTAINER, &container) else {
+            return Ok(None);
+        };
+
+        let opf = doc
+            .descendants()
+            .find(|n| n.has_tag_name("rootfile"))
+            .and_then(|n| n.attribute("full-path"))
+            .map(String::from);
+
+        match opf {
+            Some(opf) if self.names.contains(&opf) => Ok(Some(opf)),
+            Some(opf) => {
+                self.problem(CONTAINER, format!("package document `{}` is missing", opf));
+                Ok(None)
+            }
+            None => {
+                self.problem(CONTAINER, "no rootfile");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Check the manifest and spine of the package document, then every document in it.
+    fn check_package(&mut self, opf: &str) -> std::io::Result<()> {
+        let Some(content) = self.read(opf)? else {
+            return Ok(());
+        };
+        let Some(doc) = self.parse(opf, &content) else {
+            return Ok(());
+        };
+
+        let root = doc.root_element();
+        let epub3 = root.attribute("version").unwrap_or("").starts_with('3');
+
+        let mut manifest = HashMap::new();
+        let mut problems = vec![];
+
+        for item in children(root, "manifest").flat_map(|m| children(m, "item")) {
+            let (Some(id), Some(href)) = (item.attribute("id"), item.attribute("href")) else {
+                problems.push("manifest item without id or href".to_string());
+                continue;
+            };
+
+            let path = resolve(opf, href);
+
+            if !self.names.contains(&path) {
+                problems.push(format!(
+                    "manifest item `{}` points to missing `{}`",
+                    id, path
+                ));
+            }
+
+            let item = ManifestItem {
+                path,
+                media_type: item.attribute("media-type").unwrap_or("").to_string(),
+                properties: item.attribute("properties").unwrap_or("").to_string(),
+            };
+
+            if manifest.insert(id.to_string(), item).is_some() {
+                problems.push(format!("duplicate manifest id `{}`", id));
+            }
+        }
+
+        let spine = children(root, "spine").next();
+        let itemrefs = spine
+            .map(|s| children(s, "itemref").collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        if itemrefs.is_empty() {
+            problems.push("the spine is empty".to_string());
+        }
+
+        for itemref in itemrefs {
+            match itemref.attribute("idref") {
+                Some(idref) if manifest.contains_key(idref) => {}
+                Some(idref) => {
+                    problems.push(format!("spine item `{}` is not in the manifest", idref))
+                }
+                None => problems.push("spine item without idref".to_string()),
+            }
+        }
+
+        match spine.and_then(|s| s.attribute("toc")) {
+            Some(toc) if manifest.get(toc).is_some_and(|i| i.media_type == NCX) => {}
+            Some(toc) => {
+                problems.push(format!("spine toc `{}` is not an NCX in the manifest", toc))
+            }
+            None if !epub3 => problems.push("an EPUB 2 spine needs an NCX toc".to_string()),
+            None => {}
+        }
+
+        let nav = manifest
+            .values()
+            .find(|i| i.properties.split_whitespace().any(|p| p == "nav"))
+            .map(|i| i.path.clone());
+
+        if epub3 && nav.is_none() {
+            problems.push("no manifest item with the `nav` property".to_string());
+        }
+
+        for message in problems {
+            self.problem(opf, message);
+        }
+
+        let listed = manifest
+            .values()
+            .map(|i| i.path.clone())
+            .collect::<HashSet<_>>();
+
+        let mut documents = manifest
+            .into_values()
+            .filter(|i| i.media_type == XHTML && self.names.contains(&i.path))
+            .map(|i| i.path)
+            .collect::<Vec<_>>();
+        documents.sort();
+
+        for document in documents {
+            self.check_document(&document, &listed, nav.as_deref() == Some(&document))?;
+        }
+
+        Ok(())
+    }
+
+    /// The document must be well-formed and everything it links to must be in the manifest.
+    fn check_document(
+        &mut self,
+        path: &str,
+        listed: &HashSet<String>,
+        is_nav: bool,
+    ) -> std::io::Result<()> {
+        let Some(content) = self.read(path)? else {
+            return Ok(());
+        };
+        let Some(doc) = self.parse(path, &content) else {
+            return Ok(());
+        };
+
+        let mut problems = vec![];
+
+        if is_nav
+            && !doc.descendants().any(|n| {
+                n.has_tag_name("nav")
+                    && n.attributes().any(|a| {
+                        a.name() == "type" && a.value().split_whitespace().any(|t| t == "toc")
+                    })
+            })
+        {
+            problems.push("the nav document has no `<nav epub:type=\"toc\">`".to_string());
+        }
+
+        for node in doc.descendants().filter(|n| n.is_element()) {
+            for href in ["href", "src"].iter().filter_map(|a| node.attribute(*a)) {
+                if href.starts_with('#') || href.contains("://") || href.starts_with("mailto:") {
+                    continue;
+                }
+
+                let target = resolve(path, href);
+
+                if !self.names.contains(&target) {
+                    problems.push(format!(
+                        "`{}` links to missing `{}`",
+                        node.tag_name().name(),
+                        target
+                    ));
+                } else if !listed.contains(&target) {
+                    problems.push(format!("`{}` is not listed in the manifest", target));
+                }
+            }
+        }
+
+        for message in problems {
+            self.problem(path, message);
+        }
+
+        Ok(())
+    }
+
+    fn parse<'a>(&mut self, file: &str, content: &'a str) -> Option<Document<'a>> {
+        let options = ParsingOptions {
+            allow_dtd: true,
+            ..ParsingOptions::default()
+        };
+
+        match Document::parse_with_options(content, options) {
+            Ok(doc) => Some(doc),
+            Err(e) => {
+                self.problem(file, format!("not well-formed, {}", e));
+                None
+            }
+        }
+    }
+}
+
+struct ManifestItem {
+    path: String,
+    media_type: String,
+    properties: String,
+}
+
+fn children<'a, 'input>(
+    node: Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(move |n| n.has_tag_name(name))
+}
+
+/// Resolve `href` relative to the file `base`, both paths inside the archive.
+fn resolve(base: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or_default();
+    let href = href.replace("%20", " ");
+
+    let mut parts = base.split('/').collect::<Vec<_>>();
+    parts.pop();
+
+    for segment in href.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            segment => parts.push(segment),
+        }
+    }
+
+    parts.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    use super::{resolve, validate_epub};
+
+    const CONTAINER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+
+    const NAV: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+  <body><nav epub:type="toc"><ol><li><a href="0001.xhtml">1</a></li></ol></nav></body>
+</html>"#;
+
+    const CHAPTER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml"><body><p>正文</p></body></html>"#;
+
+    /// A package document with the nav and one chapter, `manifest` and `spine` are added to them.
+    fn opf(manifest: &str, spine: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="ch1" href="0001.xhtml" media-type="application/xhtml+xml"/>{}
+  </manifest>
+  <spine>
+    <itemref idref="ch1"/>{}
+  </spine>
+</package>"#,
+            manifest, spine
+        )
+    }
+
+    fn epub(entries: &[(&str, &str)]) -> Cursor<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+
+        for (name, content) in entries {
+            let method = if *name == "mimetype" {
+                CompressionMethod::Stored
+            } else {
+                CompressionMethod::Deflated
+            };
+
+            zip.start_file(*name, FileOptions::default().compression_method(method))
+                .unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+
+        let mut cursor = zip.finish().unwrap();
+        cursor.set_position(0);
+        cursor
+    }
+
+    fn problems(entries: &[(&str, &str)]) -> Vec<String> {
+        validate_epub(epub(entries))
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn valid() {
+        let opf = opf("", "");
+
+        assert_eq!(
+            problems(&[
+                ("mimetype", "application/epub+zip"),
+                ("META-INF/container.xml", CONTAINER),
+                ("OEBPS/content.opf", &opf),
+                ("OEBPS/nav.xhtml", NAV),
+                ("OEBPS/0001.xhtml", CHAPTER),
+            ]),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn mimetype_not_first() {
+        let opf = opf("", "");
+
+        assert_eq!(
+            problems(&[
+                ("META-INF/container.xml", CONTAINER),
+                ("mimetype", "application/epub+zip"),
+                ("OEBPS/content.opf", &opf),
+                ("OEBPS/nav.xhtml", NAV),
+                ("OEBPS/0001.xhtml", CHAPTER),
+            ]),
+            ["mimetype: must be the first entry, found `META-INF/container.xml`"]
+        );
+    }
+
+    #[test]
+    fn dangling_manifest_href() {
+        let opf = opf(
+            r#"
+    <item id="ch2" href="0002.xhtml" media-type="application/xhtml+xml"/>"#,
+            r#"
+    <itemref idref="ch2"/>"#,
+        );
+
+        assert_eq!(
+            problems(&[
+                ("mimetype", "application/epub+zip"),
+                ("META-INF/container.xml", CONTAINER),
+                ("OEBPS/content.opf", &opf),
+                ("OEBPS/nav.xhtml", NAV),
+                ("OEBPS/0001.xhtml", CHAPTER),
+            ]),
+            ["OEBPS/content.opf: manifest item `ch2` points to missing `OEBPS/0002.xhtml`"]
+        );
+    }
+
+    #[test]
+    fn bad_spine_idref() {
+        let opf = opf(
+            "",
+            r#"
+    <itemref idref="ch9"/>
+    <itemref/>"#,
+        );
+
+        assert_eq!(
+            problems(&[
+                ("mimetype", "application/epub+zip"),
+                ("META-INF/container.xml", CONTAINER),
+                ("OEBPS/content.opf", &opf),
+                ("OEBPS/nav.xhtml", NAV),
+                ("OEBPS/0001.xhtml", CHAPTER),
+            ]),
+            [
+                "OEBPS/content.opf: spine item `ch9` is not in the manifest",
+                "OEBPS/content.opf: spine item without idref",
+            ]
+        );
+    }
+
+    #[test]
+    fn broken_documents() {
+        let opf = opf("", "");
+        let problems = problems(&[
+            ("mimetype", "application/epub+zip"),
+            ("META-INF/container.xml", CONTAINER),
+            ("OEBPS/content.opf", &opf),
+            ("OEBPS/nav.xhtml", &NAV.replace("0001.xhtml", "0003.xhtml")),
+            ("OEBPS/0001.xhtml", &CHAPTER.replace("</p>", "")),
+        ]);
+
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with("OEBPS/0001.xhtml: not well-formed, "));
+        assert_eq!(
+            problems[1],
+            "OEBPS/nav.xhtml: `a` links to missing `OEBPS/0003.xhtml`"
+        );
+    }
+
+    #[test]
+    fn resolve_href() {
+        assert_eq!(
+            resolve("OEBPS/content.opf", "00/0001.xhtml"),
+            "OEBPS/00/0001.xhtml"
+        );
+        assert_eq!(
+            resolve("OEBPS/00/0001.xhtml", "../stylesheet.css"),
+            "OEBPS/stylesheet.css"
+        );
+        assert_eq!(
+            resolve("OEBPS/nav.xhtml", "./00/0001.xhtml#top"),
+            "OEBPS/00/0001.xhtml"
+        );
+        assert_eq!(
+            resolve("content.opf", "cover%20image.jpg"),
+            "cover image.jpg"
+        );
+    }
+}